@@ -1,22 +1,158 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::num::NonZeroUsize;
 
 use cfg_if::cfg_if;
+use chrono::{DateTime, Local, Utc};
 use leptos::*;
 use leptos_meta::*;
 use leptos_router::*;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::audience::{cue_for_groups, parse_groups, TargetedCue};
+use crate::connection::{connect_cue_socket, ConnectionIndicator};
+use crate::display::{play_cue_sound, unlock_audio, ChangeEffect, DisplayStyle};
+use crate::keyboard::{on_shortcut_key, shortcuts_enabled, KeyAction, KeyLegend};
+use crate::library::LibrarySong;
+use crate::presence::{Device, Registration, DIRECTOR_ROLE};
+use crate::protocol::{ClientMessage, Cues, ServerMessage};
+use crate::rooms::{room_path, DEFAULT_ROOM};
+use crate::setlist::{Setlist, SetlistPosition};
+use crate::vocabulary::Vocabulary;
+
 pub type SectionTuple = (Option<char>, Option<NonZeroUsize>);
 
+/// Number of cue changes kept for undo and the history panel
+const HISTORY_LENGTH: usize = 100;
+
+/// A change of the current cue, with enough of the state before it to undo it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub timestamp: DateTime<Utc>,
+    pub section: SectionTuple,
+    pub previous: SectionTuple,
+    pub previous_next: SectionTuple,
+    pub previous_position: Option<SetlistPosition>,
+}
+
+/// Everything the director controls, shared with all clients through the watch channel
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectorState {
+    pub section: SectionTuple,
+    /// Cue staged to follow the current one, shown to musicians as a heads-up
+    #[serde(default)]
+    pub next: SectionTuple,
+    pub setlist: Setlist,
+    pub position: Option<SetlistPosition>,
+    /// Recent changes of the current cue, oldest first
+    #[serde(default)]
+    pub history: VecDeque<HistoryEntry>,
+    /// Number of the latest update, increased by every change so that clients can tell which of two states is newer
+    #[serde(default)]
+    pub sequence: u64,
+    /// Number of the update that set the current cue
+    #[serde(default)]
+    pub cue_sequence: u64,
+    /// Cues for single groups only, by group. Cleared whenever the cue for everyone changes.
+    #[serde(default)]
+    pub targeted: BTreeMap<String, TargetedCue>,
+    /// Song from the library whose sections the director page offers as buttons
+    #[serde(default)]
+    pub loaded_song: Option<LibrarySong>,
+    /// Text shown to the musicians below the cue, empty for none
+    #[serde(default)]
+    pub notice: String,
+}
+
+impl DirectorState {
+    /// The cue shown to a display in `groups`
+    pub fn cue_for(&self, groups: &[String]) -> TargetedCue {
+        let everyone = TargetedCue {
+            section: self.section,
+            sequence: self.cue_sequence,
+        };
+        cue_for_groups(everyone, &self.targeted, groups)
+    }
+
+    /// Moves to the next or previous section of the setlist and cues it.
+    /// Returns `false` if there is nowhere to move to.
+    pub fn step(&mut self, forward: bool) -> bool {
+        let position = if forward {
+            self.setlist.next_position(self.position)
+        } else {
+            self.setlist.previous_position(self.position)
+        };
+        let Some(section) = position.and_then(|p| self.setlist.section_at(p)) else {
+            return false;
+        };
+        self.position = position;
+        self.section = section;
+        true
+    }
+
+    /// Cues the first section of the song at index `song`. Returns `false` if the song has no arrangement.
+    pub fn select_song(&mut self, song: usize) -> bool {
+        let Some(position) = self.setlist.song_start(song).filter(|p| p.song == song) else {
+            return false;
+        };
+        self.position = Some(position);
+        self.section = self.setlist.section_at(position).unwrap_or_default();
+        true
+    }
+
+    /// Makes the staged cue the current one. Returns `false` if no cue is staged.
+    pub fn promote_next(&mut self) -> bool {
+        if self.next.0.is_none() {
+            return false;
+        }
+        self.section = std::mem::take(&mut self.next);
+        true
+    }
+
+    pub fn replace_setlist(&mut self, setlist: Setlist) {
+        self.setlist = setlist;
+        // Indices into the old setlist are meaningless now
+        self.position = None;
+    }
+
+    /// Adds the change to the current cue from the given previous state to the history, dropping the oldest entry if
+    /// it is full
+    pub fn record_change(
+        &mut self,
+        previous: SectionTuple,
+        previous_next: SectionTuple,
+        previous_position: Option<SetlistPosition>,
+        timestamp: DateTime<Utc>,
+    ) {
+        if self.history.len() >= HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(HistoryEntry {
+            timestamp,
+            section: self.section,
+            previous,
+            previous_next,
+            previous_position,
+        });
+    }
+
+    /// Reverts the most recent cue change. Returns `false` if there is no history.
+    pub fn undo(&mut self) -> bool {
+        let Some(entry) = self.history.pop_back() else {
+            return false;
+        };
+        self.section = entry.previous;
+        self.next = entry.previous_next;
+        // The setlist may have been replaced since
+        self.position = entry
+            .previous_position
+            .filter(|position| self.setlist.section_at(*position).is_some());
+        true
+    }
+}
+
 #[derive(Clone, Debug, Error, Serialize, Deserialize)]
 enum SectionLoadError {
-    #[error("could not read location host")]
-    LocationHostError,
-    #[error("could not open WebSocket: {0}")]
-    WebSocketOpenError(String),
-    #[error("error receiving message from WebSocket: {0}")]
-    WebSocketError(String),
     #[error("server error: {0}")]
     ServerError(#[from] ServerFnErrorErr),
 }
@@ -27,50 +163,357 @@ cfg_if! {
         use std::sync::Arc;
 
         use axum::extract::ws::{self, WebSocket};
-        use axum::extract::FromRef;
+        use axum::extract::{ConnectInfo, FromRef};
         use futures::StreamExt;
 
+        use crate::auth::Auth;
+        use crate::control::{self, ControlAction};
+        use crate::display::DisplayProfiles;
+        use crate::library::SongStore;
+        use crate::presence::HEARTBEAT_INTERVAL;
+        use crate::protocol::{decode, Encoding, Frame};
+        use crate::recorder::CueLog;
+        use crate::rooms::{Room, RoomRegistry};
+
         #[derive(FromRef, Clone)]
         pub struct AppState {
             pub leptos_options: LeptosOptions,
-            pub section_tx: Arc<tokio::sync::watch::Sender<SectionTuple>>,
-            pub section_rx: tokio::sync::watch::Receiver<SectionTuple>,
+            pub rooms: Arc<RoomRegistry>,
+            pub vocabulary: Arc<Vocabulary>,
+            pub display_profiles: Arc<DisplayProfiles>,
+            /// `None` if the library is disabled
+            pub library: Option<Arc<dyn SongStore>>,
+            pub cue_log: Option<Arc<CueLog>>,
+            pub auth: Arc<Auth>,
         }
 
-        fn get_section_tx(cx: Scope) -> Result<Arc<tokio::sync::watch::Sender<SectionTuple>>, ServerFnError> {
-            use_context::<Arc<tokio::sync::watch::Sender<SectionTuple>>>(cx).ok_or_else(|| ServerFnError::ServerError("Section TX missing".to_string()))
+        fn get_vocabulary_context(cx: Scope) -> Result<Arc<Vocabulary>, ServerFnError> {
+            use_context::<Arc<Vocabulary>>(cx).ok_or_else(|| ServerFnError::ServerError("Vocabulary missing".to_string()))
         }
 
-        fn get_section_rx(cx: Scope) -> Result<tokio::sync::watch::Receiver<SectionTuple>, ServerFnError> {
-            use_context::<tokio::sync::watch::Receiver<SectionTuple>>(cx).ok_or_else(|| ServerFnError::ServerError("Section RX missing".to_string()))
+        fn get_display_profiles_context(cx: Scope) -> Result<Arc<DisplayProfiles>, ServerFnError> {
+            use_context::<Arc<DisplayProfiles>>(cx).ok_or_else(|| ServerFnError::ServerError("Display profiles missing".to_string()))
         }
 
-        pub async fn section_socket(mut socket: WebSocket, mut section_rx: tokio::sync::watch::Receiver<SectionTuple>, socket_addr: SocketAddr) {
+        fn get_library_context(cx: Scope) -> Result<Arc<dyn SongStore>, ServerFnError> {
+            use_context::<Option<Arc<dyn SongStore>>>(cx)
+                .ok_or_else(|| ServerFnError::ServerError("Song library missing".to_string()))?
+                .ok_or_else(|| ServerFnError::ServerError("The song library is disabled".to_string()))
+        }
+
+        fn get_auth_context(cx: Scope) -> Result<Arc<Auth>, ServerFnError> {
+            use_context::<Arc<Auth>>(cx).ok_or_else(|| ServerFnError::ServerError("Auth missing".to_string()))
+        }
+
+        fn client_addr(cx: Scope) -> Result<SocketAddr, ServerFnError> {
+            use_context::<ConnectInfo<SocketAddr>>(cx)
+                .map(|ConnectInfo(addr)| addr)
+                .ok_or_else(|| ServerFnError::ServerError("Client address missing".to_string()))
+        }
+
+        /// A logged in director, recorded in the audit log with every change they make
+        struct Director {
+            name: String,
+            addr: SocketAddr,
+        }
+
+        impl Director {
+            fn audit(&self, room: &str, action: std::fmt::Arguments) {
+                log::info!(target: "audit", "{} in room {}: {}", self.source(), room, action);
+            }
+
+            /// Applies a cue change the same way as the control inputs do
+            fn apply(
+                &self,
+                cx: Scope,
+                room: &str,
+                action: ControlAction,
+                section: Option<SectionTuple>,
+            ) -> Result<(), ServerFnError> {
+                let vocabulary = get_vocabulary_context(cx)?;
+                control::apply_section_action(&*get_room(cx, room)?, &vocabulary, action, section, &self.source())
+                    .map_err(|err| ServerFnError::Args(err.to_string()))
+            }
+
+            /// Name and address of the director, as recorded in the audit log
+            fn source(&self) -> String {
+                format!("{} ({})", self.name, self.addr.ip())
+            }
+
+            /// Records a change that isn't tied to a room, such as to the song library
+            fn audit_server(&self, action: std::fmt::Arguments) {
+                log::info!(target: "audit", "{}: {}", self.source(), action);
+            }
+        }
+
+        /// Returns the director making the request, or an error for viewers
+        fn require_director(cx: Scope) -> Result<Director, ServerFnError> {
+            let request = use_context::<leptos_axum::RequestParts>(cx)
+                .ok_or_else(|| ServerFnError::ServerError("Request missing".to_string()))?;
+            let name = get_auth_context(cx)?
+                .director(&request.headers)
+                .ok_or_else(|| ServerFnError::ServerError("Log in as a director first".to_string()))?;
+            Ok(Director {
+                name,
+                addr: client_addr(cx)?,
+            })
+        }
+
+        fn get_room(cx: Scope, room: &str) -> Result<Arc<Room>, ServerFnError> {
+            use_context::<Arc<RoomRegistry>>(cx)
+                .ok_or_else(|| ServerFnError::ServerError("Room registry missing".to_string()))?
+                .get_or_create(room)
+                .map_err(|err| ServerFnError::Args(err.to_string()))
+        }
+
+        /// Query of the cue socket and its fallbacks, e.g. `/ws?room=youth&name=Drums`
+        #[derive(Deserialize)]
+        pub struct SocketQuery {
+            pub room: Option<String>,
+            #[serde(flatten)]
+            pub device: Registration,
+        }
+
+        /// The other end of a cue socket
+        pub struct SocketClient {
+            pub addr: SocketAddr,
+            pub name: String,
+            pub role: String,
+            /// Groups whose targeted cues the client gets as well as the cues for everyone
+            pub groups: Vec<String>,
+            /// Directors are kept up to date about the connected devices and all targeted cues
+            pub director: bool,
+        }
+
+        impl SocketClient {
+            /// The client at `addr` registered as `device`. Devices that don't name themselves are listed under the
+            /// logged in director's name or their address.
+            pub fn new(addr: SocketAddr, device: Registration, auth: &Auth, headers: &http::HeaderMap) -> Self {
+                let director = auth.director(headers);
+                let name = device
+                    .name
+                    .filter(|name| !name.is_empty())
+                    .or_else(|| director.clone().filter(|_| auth.is_enabled()))
+                    .unwrap_or_else(|| addr.ip().to_string());
+                SocketClient {
+                    addr,
+                    name,
+                    role: device.role.unwrap_or_default(),
+                    groups: device.groups.as_deref().map(parse_groups).unwrap_or_default(),
+                    director: director.is_some(),
+                }
+            }
+        }
+
+        /// Sends the changes of the room's cues to the client. Clients that negotiated a subprotocol get
+        /// [`ServerMessage`]s in its encoding, and others get the current and staged cues as a bare string.
+        /// The client is listed in the room's presence until the socket closes.
+        pub async fn section_socket(mut socket: WebSocket, room: Arc<Room>, client: SocketClient) {
+            let socket_addr = client.addr;
+            let encoding = socket
+                .protocol()
+                .and_then(|protocol| protocol.to_str().ok())
+                .and_then(Encoding::from_subprotocol);
+            let mut section_rx = room.subscribe();
+            // State the client has been told about so far
+            let mut known = section_rx.borrow_and_update().clone();
+            let mut device = room.presence().join(client.name.clone(), client.role.clone(), client.groups.clone());
+            let mut devices_rx = room.presence().subscribe();
+            // Only directors with a client that understands the messages get the devices
+            let devices_encoding = encoding.filter(|_| client.director);
+            log::debug!("Socket with {} joined room {} using {:?}", socket_addr, room.id(), encoding);
+            let mut result = send_snapshot(&mut socket, encoding, &client, &known).await;
+            if let (Ok(()), Some(encoding)) = (&result, devices_encoding) {
+                let devices = devices_rx.borrow_and_update().clone();
+                result = send_message(&mut socket, encoding, &ServerMessage::Devices { devices }).await;
+            }
+            if let Err(err) = result {
+                log::warn!("Error sending to {}: {}", socket_addr, err);
+                return;
+            }
+            let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+            let mut ping_id = 0;
             loop {
-                tokio::select! {
-                    changed = section_rx.changed() => if changed.is_ok() {
-                        let message = section_segments_to_string(&section_rx.borrow());
-                        log::debug!("Sending {}", message);
-                        if let Err(err) = socket.send(ws::Message::Text(message)).await {
-                            log::warn!("Error sending to {}: {}", socket_addr, err);
+                let result = tokio::select! {
+                    changed = section_rx.changed() => {
+                        if changed.is_err() {
+                            // Channel has closed. Should never actually happen
+                            let _ = socket.close().await;
                             return;
                         }
-                    } else {
-                        // Channel has closed. Should never actually happen
-                        let _ = socket.close().await;
-                        return;
-                    },
-                    Some(Ok(ws::Message::Close(_))) = socket.next() => {
-                        log::debug!("Socket with {} closed", socket_addr);
-                        return;
+                        let state = section_rx.borrow_and_update().clone();
+                        let result = send_changes(&mut socket, encoding, &client, &known, &state).await;
+                        known = state;
+                        result
                     }
+                    Ok(()) = devices_rx.changed(), if devices_encoding.is_some() => {
+                        let devices = devices_rx.borrow_and_update().clone();
+                        match devices_encoding {
+                            Some(encoding) => send_message(&mut socket, encoding, &ServerMessage::Devices { devices }).await,
+                            None => Ok(()),
+                        }
+                    }
+                    _ = heartbeat.tick() => {
+                        if !device.check() {
+                            log::debug!("Closing silent socket with {}", socket_addr);
+                            let _ = socket.close().await;
+                            return;
+                        }
+                        ping_id += 1;
+                        match encoding {
+                            Some(encoding) => send_message(&mut socket, encoding, &ServerMessage::Ping { id: ping_id }).await,
+                            // Browsers answer WebSocket pings by themselves
+                            None => socket.send(ws::Message::Ping(Vec::new())).await,
+                        }
+                    }
+                    message = socket.next() => {
+                        device.seen();
+                        let frame = match message {
+                            Some(Ok(ws::Message::Text(text))) => Frame::Text(text),
+                            Some(Ok(ws::Message::Binary(bytes))) => Frame::Binary(bytes),
+                            Some(Ok(ws::Message::Close(_))) | None => {
+                                log::debug!("Socket with {} closed", socket_addr);
+                                return;
+                            }
+                            // Pings are answered by axum itself, and pongs only count as a sign of life
+                            Some(Ok(_)) => continue,
+                            Some(Err(err)) => {
+                                log::warn!("Error receiving from {}: {}", socket_addr, err);
+                                return;
+                            }
+                        };
+                        match (encoding, handle_client_message(&frame, &room, &client, |sequence| device.ack(sequence))) {
+                            (Some(encoding), Some(response)) => send_message(&mut socket, encoding, &response).await,
+                            // Legacy clients aren't expected to send anything
+                            _ => Ok(()),
+                        }
+                    }
+                };
+                if let Err(err) = result {
+                    log::warn!("Error sending to {}: {}", socket_addr, err);
+                    return;
+                }
+            }
+        }
+
+        async fn send_snapshot(
+            socket: &mut WebSocket,
+            encoding: Option<Encoding>,
+            client: &SocketClient,
+            state: &DirectorState,
+        ) -> Result<(), axum::Error> {
+            let Some(encoding) = encoding else {
+                return socket.send(ws::Message::Text(cues_to_string(&state.section, &state.next))).await;
+            };
+            for message in &snapshot_messages(client, state) {
+                send_message(socket, encoding, message).await?;
+            }
+            Ok(())
+        }
+
+        /// Everything a newly connected client needs to know about `state`
+        pub fn snapshot_messages(client: &SocketClient, state: &DirectorState) -> Vec<ServerMessage> {
+            let cue = state.cue_for(&client.groups);
+            let mut messages = vec![ServerMessage::Snapshot {
+                sequence: state.sequence,
+                cue_sequence: cue.sequence,
+                section: cue.section,
+                next: state.next,
+            }];
+            if client.director {
+                messages.push(ServerMessage::Targeted {
+                    cues: state.targeted.clone(),
+                });
+            }
+            // Even when empty, so that a client that missed the notice being cleared clears it too
+            messages.push(ServerMessage::Message {
+                text: state.notice.clone(),
+            });
+            messages
+        }
+
+        /// Tells a client that knew about `known` what is different in `state`
+        async fn send_changes(
+            socket: &mut WebSocket,
+            encoding: Option<Encoding>,
+            client: &SocketClient,
+            known: &DirectorState,
+            state: &DirectorState,
+        ) -> Result<(), axum::Error> {
+            let Some(encoding) = encoding else {
+                if (state.section, state.next) == (known.section, known.next) {
+                    return Ok(());
                 }
+                return socket.send(ws::Message::Text(cues_to_string(&state.section, &state.next))).await;
+            };
+            for message in &change_messages(client, known, state) {
+                send_message(socket, encoding, message).await?;
+            }
+            Ok(())
+        }
+
+        /// What a client that knew about `known` needs to be told about `state`
+        pub fn change_messages(client: &SocketClient, known: &DirectorState, state: &DirectorState) -> Vec<ServerMessage> {
+            let sequence = state.sequence;
+            let mut messages = Vec::new();
+            let cue = state.cue_for(&client.groups);
+            if cue.sequence != known.cue_for(&client.groups).sequence {
+                messages.push(ServerMessage::Cue {
+                    sequence: cue.sequence,
+                    section: cue.section,
+                });
+            }
+            if state.next != known.next {
+                messages.push(ServerMessage::NextCue { sequence, section: state.next });
+            }
+            if state.setlist != known.setlist || state.position != known.position || state.loaded_song != known.loaded_song {
+                messages.push(ServerMessage::SetlistChanged { sequence });
+            }
+            if client.director && state.targeted != known.targeted {
+                messages.push(ServerMessage::Targeted {
+                    cues: state.targeted.clone(),
+                });
+            }
+            if state.notice != known.notice {
+                messages.push(ServerMessage::Message {
+                    text: state.notice.clone(),
+                });
+            }
+            messages
+        }
+
+        async fn send_message(socket: &mut WebSocket, encoding: Encoding, message: &ServerMessage) -> Result<(), axum::Error> {
+            let frame = match encoding.encode(message) {
+                Ok(Frame::Text(text)) => ws::Message::Text(text),
+                Ok(Frame::Binary(bytes)) => ws::Message::Binary(bytes),
+                Err(err) => {
+                    log::error!("{}", err);
+                    return Ok(());
+                }
+            };
+            socket.send(frame).await
+        }
+
+        /// Handles a message from `client`, returning the server's answer, if any. `ack` records that the client has
+        /// acknowledged the current cue.
+        pub fn handle_client_message(frame: &Frame, room: &Room, client: &SocketClient, ack: impl FnOnce(u64)) -> Option<ServerMessage> {
+            match decode::<ClientMessage>(frame) {
+                Ok(ClientMessage::Ping { id }) => Some(ServerMessage::Pong { id }),
+                Ok(ClientMessage::Pong { .. }) => None,
+                Ok(ClientMessage::Ack { sequence }) => {
+                    // Late acknowledgements of a previous cue don't count for the current one
+                    if sequence == room.state().cue_for(&client.groups).sequence {
+                        ack(sequence);
+                    }
+                    None
+                }
+                Err(err) => Some(ServerMessage::Error { message: err.to_string() }),
             }
         }
     }
 }
 
-fn section_segments_to_string(segments: &SectionTuple) -> String {
+pub fn section_segments_to_string(segments: &SectionTuple) -> String {
     if let Some(sec) = segments.0 {
         if let Some(num) = segments.1 {
             format!("{}{}", sec, num)
@@ -82,20 +525,280 @@ fn section_segments_to_string(segments: &SectionTuple) -> String {
     }
 }
 
+/// Inverse of [`section_segments_to_string`], taking cue codes in either case, e.g. `v2` for `V2`. Returns `None` for
+/// an empty string or an invalid number.
+pub fn section_string_to_segments(section: &str) -> Option<SectionTuple> {
+    let mut section_chars = section.chars();
+    let section_type = section_chars.next()?.to_ascii_uppercase();
+    let section_number = section_chars.as_str();
+    if section_number.is_empty() {
+        Some((Some(section_type), None))
+    } else {
+        Some((Some(section_type), Some(section_number.parse().ok()?)))
+    }
+}
+
+/// Separates the current cue from the staged one in [`cues_to_string`]
+const NEXT_SEPARATOR: &str = " → ";
+
+/// Current and staged cues as a single string, e.g. `V2 → C`. Just the current cue if there is no staged cue.
+pub fn cues_to_string(current: &SectionTuple, next: &SectionTuple) -> String {
+    let current = section_segments_to_string(current);
+    if next.0.is_some() {
+        format!("{}{}{}", current, NEXT_SEPARATOR, section_segments_to_string(next))
+    } else {
+        current
+    }
+}
+
+/// Returns the current cue for a display in `groups`, and the staged cue
 #[server(GetSection, "/api", "Cbor")]
-async fn get_section(cx: Scope) -> Result<SectionTuple, ServerFnError> {
-    Ok(get_section_rx(cx)?.borrow().clone())
+async fn get_section(cx: Scope, room: String, groups: Vec<String>) -> Result<Cues, ServerFnError> {
+    let state = get_room(cx, &room)?.state();
+    let cue = state.cue_for(&groups);
+    Ok(Cues {
+        sequence: state.sequence,
+        cue_sequence: cue.sequence,
+        section: cue.section,
+        next: state.next,
+    })
 }
 
 #[server(SetSection, "/api", "Cbor")]
-async fn set_section(cx: Scope, section: SectionTuple) -> Result<(), ServerFnError> {
-    log::debug!("Update section to {:?}", section);
-    let tx = get_section_tx(cx)?;
-    tx.send_modify(|s| *s = section);
+async fn set_section(cx: Scope, room: String, section: SectionTuple) -> Result<(), ServerFnError> {
+    require_director(cx)?.apply(cx, &room, ControlAction::Set, Some(section))
+}
+
+/// Sets the current cue for the group `audience` only
+#[server(SetTargetedCue, "/api", "Cbor")]
+async fn set_targeted_cue(cx: Scope, room: String, audience: String, section: SectionTuple) -> Result<(), ServerFnError> {
+    let director = require_director(cx)?;
+    let vocabulary = get_vocabulary_context(cx)?;
+    vocabulary
+        .validate_group(&audience)
+        .and_then(|()| vocabulary.validate(&section))
+        .map_err(|err| ServerFnError::Args(err.to_string()))?;
+    director.audit(
+        &room,
+        format_args!("set section for {} to {}", audience, section_segments_to_string(&section)),
+    );
+    get_room(cx, &room)?.target(audience, section);
+
+    Ok(())
+}
+
+#[server(SetNextSection, "/api", "Cbor")]
+async fn set_next_section(cx: Scope, room: String, section: SectionTuple) -> Result<(), ServerFnError> {
+    require_director(cx)?.apply(cx, &room, ControlAction::Stage, Some(section))
+}
+
+#[server(PromoteNextSection, "/api", "Cbor")]
+async fn promote_next_section(cx: Scope, room: String) -> Result<(), ServerFnError> {
+    require_director(cx)?.apply(cx, &room, ControlAction::Go, None)
+}
+
+#[server(UndoSection, "/api", "Cbor")]
+async fn undo_section(cx: Scope, room: String) -> Result<(), ServerFnError> {
+    require_director(cx)?.apply(cx, &room, ControlAction::Undo, None)
+}
+
+/// Returns the recent cue changes, newest first
+#[server(GetHistory, "/api", "Cbor")]
+async fn get_history(cx: Scope, room: String) -> Result<Vec<HistoryEntry>, ServerFnError> {
+    require_director(cx)?;
+    Ok(get_room(cx, &room)?.state().history.into_iter().rev().collect())
+}
+
+#[server(GetSetlist, "/api", "Cbor")]
+async fn get_setlist(cx: Scope, room: String) -> Result<(Setlist, Option<SetlistPosition>), ServerFnError> {
+    require_director(cx)?;
+    let state = get_room(cx, &room)?.state();
+    Ok((state.setlist, state.position))
+}
+
+#[server(SetSetlist, "/api")]
+async fn set_setlist(cx: Scope, room: String, setlist: String) -> Result<(), ServerFnError> {
+    let director = require_director(cx)?;
+    let setlist = Setlist::parse(&setlist).map_err(|err| ServerFnError::ServerError(err.to_string()))?;
+    let vocabulary = get_vocabulary_context(cx)?;
+    for song in &setlist.songs {
+        for section in &song.arrangement {
+            vocabulary
+                .validate(section)
+                .map_err(|err| ServerFnError::ServerError(format!("{}: {}", song.title, err)))?;
+        }
+    }
+    director.audit(&room, format_args!("replaced setlist with {} songs", setlist.songs.len()));
+    get_room(cx, &room)?.modify(|s| {
+        s.replace_setlist(setlist);
+        true
+    });
 
     Ok(())
 }
 
+#[server(NextSection, "/api", "Cbor")]
+async fn next_section(cx: Scope, room: String) -> Result<(), ServerFnError> {
+    require_director(cx)?.apply(cx, &room, ControlAction::Next, None)
+}
+
+#[server(PreviousSection, "/api", "Cbor")]
+async fn previous_section(cx: Scope, room: String) -> Result<(), ServerFnError> {
+    require_director(cx)?.apply(cx, &room, ControlAction::Previous, None)
+}
+
+#[server(SelectSong, "/api", "Cbor")]
+async fn select_song(cx: Scope, room: String, song: usize) -> Result<(), ServerFnError> {
+    require_director(cx)?.audit(&room, format_args!("selected song {}", song + 1));
+    get_room(cx, &room)?.modify(|s| s.select_song(song));
+
+    Ok(())
+}
+
+/// Shows `text` to the musicians in the room until it is replaced. An empty text clears it.
+#[server(SendNotice, "/api")]
+async fn send_notice(cx: Scope, room: String, text: String) -> Result<(), ServerFnError> {
+    let director = require_director(cx)?;
+    let text = text.trim().to_string();
+    if text.is_empty() {
+        director.audit(&room, format_args!("cleared notice"));
+    } else {
+        director.audit(&room, format_args!("sent notice \"{}\"", text));
+    }
+    get_room(cx, &room)?.modify(|s| {
+        let changed = s.notice != text;
+        s.notice = text;
+        changed
+    });
+
+    Ok(())
+}
+
+/// Every song in the library, ordered by title
+#[server(GetLibrary, "/api", "Cbor")]
+async fn get_library(cx: Scope) -> Result<Vec<LibrarySong>, ServerFnError> {
+    require_director(cx)?;
+    get_library_context(cx)?
+        .list()
+        .map_err(|err| ServerFnError::ServerError(err.to_string()))
+}
+
+/// Adds the song in the ChordPro `chart` to the library, replacing the song with the same title
+#[server(ImportSong, "/api")]
+async fn import_song(cx: Scope, chart: String) -> Result<LibrarySong, ServerFnError> {
+    let director = require_director(cx)?;
+    let song = LibrarySong::from_chordpro(&chart, &*get_vocabulary_context(cx)?)
+        .map_err(|err| ServerFnError::ServerError(err.to_string()))?;
+    get_library_context(cx)?
+        .save(&song)
+        .map_err(|err| ServerFnError::ServerError(err.to_string()))?;
+    director.audit_server(format_args!("imported song {} into the library", song.title));
+
+    Ok(song)
+}
+
+#[server(DeleteLibrarySong, "/api", "Cbor")]
+async fn delete_library_song(cx: Scope, id: String) -> Result<(), ServerFnError> {
+    let director = require_director(cx)?;
+    let deleted = get_library_context(cx)?
+        .delete(&id)
+        .map_err(|err| ServerFnError::ServerError(err.to_string()))?;
+    if deleted {
+        director.audit_server(format_args!("deleted song {} from the library", id));
+    }
+
+    Ok(())
+}
+
+/// Loads the library song `id` into the room, or unloads the current one if `id` is `None`
+#[server(LoadLibrarySong, "/api", "Cbor")]
+async fn load_library_song(cx: Scope, room: String, id: Option<String>) -> Result<(), ServerFnError> {
+    let director = require_director(cx)?;
+    let song = match &id {
+        Some(id) => {
+            let song = get_library_context(cx)?
+                .get(id)
+                .map_err(|err| ServerFnError::ServerError(err.to_string()))?
+                .ok_or_else(|| ServerFnError::Args(format!("No song \"{}\" in the library", id)))?;
+            // The vocabulary may have changed since the song was imported
+            let vocabulary = get_vocabulary_context(cx)?;
+            for section in &song.sections {
+                vocabulary
+                    .validate(&section.section)
+                    .map_err(|err| ServerFnError::ServerError(format!("{}: {}, import it again", song.title, err)))?;
+            }
+            Some(song)
+        }
+        None => None,
+    };
+    match &song {
+        Some(song) => director.audit(&room, format_args!("loaded song {}", song.title)),
+        None => director.audit(&room, format_args!("unloaded song")),
+    }
+    get_room(cx, &room)?.modify(|s| {
+        let changed = s.loaded_song != song;
+        s.loaded_song = song;
+        changed
+    });
+
+    Ok(())
+}
+
+#[server(GetLoadedSong, "/api", "Cbor")]
+async fn get_loaded_song(cx: Scope, room: String) -> Result<Option<LibrarySong>, ServerFnError> {
+    require_director(cx)?;
+    Ok(get_room(cx, &room)?.state().loaded_song)
+}
+
+#[server(GetVocabulary, "/api", "Cbor")]
+async fn get_vocabulary(cx: Scope) -> Result<Vocabulary, ServerFnError> {
+    Ok((*get_vocabulary_context(cx)?).clone())
+}
+
+#[server(GetDisplayProfile, "/api", "Cbor")]
+async fn get_display_profile(cx: Scope, name: String) -> Result<DisplayStyle, ServerFnError> {
+    get_display_profiles_context(cx)?
+        .get(&name)
+        .cloned()
+        .ok_or_else(|| ServerFnError::ServerError(format!("No display profile named \"{}\"", name)))
+}
+
+/// Starts a director session if `pin` is right, then returns to `next`
+#[server(DirectorLogin, "/api")]
+async fn director_login(cx: Scope, pin: String, name: String, next: String) -> Result<(), ServerFnError> {
+    use http::header::{HeaderValue, SET_COOKIE};
+
+    use crate::auth::{login_name, login_redirect_target};
+
+    let auth = get_auth_context(cx)?;
+    let addr = client_addr(cx)?;
+    let name = login_name(&name);
+    if auth.is_enabled() {
+        let token = auth.login(addr.ip(), &pin, &name).map_err(|err| {
+            log::warn!(target: "audit", "Failed director login as {} from {}: {}", name, addr.ip(), err);
+            ServerFnError::ServerError(err.to_string())
+        })?;
+        log::info!(target: "audit", "{} logged in as director from {}", name, addr.ip());
+        let cookie = HeaderValue::from_str(&auth.session_cookie(&token))
+            .map_err(|err| ServerFnError::ServerError(err.to_string()))?;
+        use_context::<leptos_axum::ResponseOptions>(cx)
+            .ok_or_else(|| ServerFnError::ServerError("Response options missing".to_string()))?
+            .insert_header(SET_COOKIE, cookie);
+    }
+    leptos_axum::redirect(cx, login_redirect_target(&next));
+
+    Ok(())
+}
+
+/// The room named in the current route, or the default room for routes without one
+fn use_room(cx: Scope) -> String {
+    use_params_map(cx)
+        .get_untracked()
+        .get("id")
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_ROOM.to_string())
+}
+
 #[component]
 pub fn App(cx: Scope) -> impl IntoView {
     // Provides context that manages stylesheets, titles, meta tags, etc.
@@ -119,6 +822,13 @@ pub fn App(cx: Scope) -> impl IntoView {
                 <Routes>
                     <Route path="" view=|cx| view! { cx, <Director/> }/>
                     <Route path="view" view=|cx| view! {cx, <SectionDisplay/>}/>
+                    <Route path="setlist" view=|cx| view! {cx, <SetlistEditor/>}/>
+                    <Route path="library" view=|cx| view! {cx, <LibraryPage/>}/>
+                    <Route path="login" view=|cx| view! {cx, <LoginPage/>}/>
+                    <Route path="room/:id" view=|cx| view! { cx, <Director/> }/>
+                    <Route path="room/:id/view" view=|cx| view! {cx, <SectionDisplay/>}/>
+                    <Route path="room/:id/setlist" view=|cx| view! {cx, <SetlistEditor/>}/>
+                    <Route path="room/:id/library" view=|cx| view! {cx, <LibraryPage/>}/>
                 </Routes>
             </main>
         </Router>
@@ -127,39 +837,138 @@ pub fn App(cx: Scope) -> impl IntoView {
 
 #[component]
 fn Director(cx: Scope) -> impl IntoView {
-    let section_resource = create_resource(cx, || (), move |_| get_section(cx));
+    let room = store_value(cx, use_room(cx));
+    // Newest cues from any source, so that a server function response overtaken by the socket is ignored
+    let latest_cues = store_value(cx, None::<Cues>);
+    let section_resource = create_resource(
+        cx,
+        || (),
+        move |_| async move {
+            let cues = get_section(cx, room.get_value(), Vec::new()).await?;
+            latest_cues.update_value(|latest| {
+                cues.merge_into(latest);
+            });
+            Ok::<_, ServerFnError>(latest_cues.get_value().unwrap_or(cues))
+        },
+    );
     let set_section_action = create_server_action::<SetSection>(cx);
+    let set_next_section_action = create_server_action::<SetNextSection>(cx);
+    let promote_next_section_action = create_server_action::<PromoteNextSection>(cx);
+    let undo_section_action = create_server_action::<UndoSection>(cx);
+    let history_resource = create_resource(cx, || (), move |_| get_history(cx, room.get_value()));
+    let setlist_resource = create_resource(cx, || (), move |_| get_setlist(cx, room.get_value()));
+    let vocabulary_resource = create_resource(cx, || (), move |_| get_vocabulary(cx));
+    // While staging, the cue buttons edit the next cue instead of the current one
+    let (staging, set_staging) = create_signal(cx, false);
+    // While a group is selected, the cue buttons edit the cue for that group only
+    let (audience, set_audience) = create_signal(cx, None::<String>);
+    let (targeted, set_targeted) = create_signal(cx, BTreeMap::<String, TargetedCue>::new());
+    let set_targeted_cue_action = create_server_action::<SetTargetedCue>(cx);
+    let next_section_action = create_server_action::<NextSection>(cx);
+    let previous_section_action = create_server_action::<PreviousSection>(cx);
+    let select_song_action = create_server_action::<SelectSong>(cx);
+    let library_resource = create_resource(cx, || (), move |_| get_library(cx));
+    let loaded_song_resource = create_resource(cx, || (), move |_| get_loaded_song(cx, room.get_value()));
+    let load_song_action = create_server_action::<LoadLibrarySong>(cx);
+    let send_notice_action = create_server_action::<SendNotice>(cx);
+    // Text shown to the musicians, kept up to date by the server
+    let (notice, set_notice) = create_signal(cx, String::new());
+    let loaded_song = move || loaded_song_resource.read(cx).and_then(Result::ok).flatten();
 
-    let change_section_type = move |ch| {
-        let new_section = (Some(ch), None);
-        section_resource.set(Ok(new_section));
-        set_section_action.dispatch(SetSection {
-            section: new_section,
+    // Applies `update` to the targeted, current or staged cue, depending on the mode, and sends the result
+    let update_section = move |update: &dyn Fn(&mut SectionTuple)| {
+        if let Some(audience) = audience.get_untracked() {
+            let mut section = targeted
+                .with_untracked(|targeted| targeted.get(&audience).map(|cue| cue.section))
+                .unwrap_or_default();
+            update(&mut section);
+            set_targeted_cue_action.dispatch(SetTargetedCue {
+                room: room.get_value(),
+                audience,
+                section,
+            });
+            return;
+        }
+        let staging = staging.get_untracked();
+        section_resource.update(|cues| {
+            if let Some(Ok(cues)) = cues {
+                update(if staging { &mut cues.next } else { &mut cues.section });
+            }
         });
+        let Some(Ok(cues)) = section_resource.read(cx) else {
+            return;
+        };
+        latest_cues.set_value(Some(cues));
+        if staging {
+            set_next_section_action.dispatch(SetNextSection {
+                room: room.get_value(),
+                section: cues.next,
+            });
+        } else {
+            set_section_action.dispatch(SetSection {
+                room: room.get_value(),
+                section: cues.section,
+            });
+        }
     };
-    let clear_section = move || {
-        let new_section = (None, None);
-        section_resource.set(Ok(new_section));
-        set_section_action.dispatch(SetSection {
-            section: new_section,
-        });
+    let change_section_type = move |ch| update_section(&|section| *section = (Some(ch), None));
+    let clear_section = move || update_section(&|section| *section = (None, None));
+    let set_section_number = move |num| update_section(&|section| section.1 = num);
+    let promote_next_section = move || {
+        set_staging.set(false);
+        promote_next_section_action.dispatch(PromoteNextSection { room: room.get_value() });
     };
-    let set_section_number = move |num| {
-        section_resource.update(|sec| {
-            if let Some(Ok(section)) = sec {
-                section.1 = num;
-            }
-        });
-        let new_section = section_resource.read(cx).unwrap().unwrap();
-        set_section_action.dispatch(SetSection {
-            section: new_section,
-        });
+    // Only numbers within the range of the cue being edited can be added
+    let number_allowed = move |vocabulary: &Vocabulary, number: NonZeroUsize| {
+        let Some(Ok(cues)) = section_resource.read(cx) else {
+            return false;
+        };
+        let section = match audience.get() {
+            Some(audience) => targeted.with(|targeted| targeted.get(&audience).map(|cue| cue.section)),
+            None if staging.get() => Some(cues.next),
+            None => Some(cues.section),
+        };
+        let Some(code) = section.and_then(|section| section.0) else {
+            return false;
+        };
+        vocabulary.cue(code).is_some_and(|cue| number.get() <= cue.numbers)
     };
+
+    // Whether this device uses the keyboard shortcuts. Only known once hydrated, as it's stored in the browser.
+    let (shortcuts, set_shortcuts) = create_signal(cx, true);
+    create_effect(cx, move |_| set_shortcuts.set(shortcuts_enabled()));
+    on_shortcut_key(cx, move |key| {
+        if !shortcuts.get_untracked() {
+            return false;
+        }
+        let Some(Ok(vocabulary)) = vocabulary_resource.read(cx) else {
+            return false;
+        };
+        let Some(action) = vocabulary.keys.action(&vocabulary.cues, key) else {
+            return false;
+        };
+        match action {
+            KeyAction::Cue(code) => change_section_type(code),
+            KeyAction::Number(number) => {
+                if number_allowed(&vocabulary, number) {
+                    set_section_number(Some(number));
+                }
+            }
+            KeyAction::Clear => clear_section(),
+            KeyAction::Undo => undo_section_action.dispatch(UndoSection { room: room.get_value() }),
+            KeyAction::Stage => set_staging.update(|staging| *staging = !*staging),
+            KeyAction::Go => promote_next_section(),
+            KeyAction::Next => next_section_action.dispatch(NextSection { room: room.get_value() }),
+            KeyAction::Previous => previous_section_action.dispatch(PreviousSection { room: room.get_value() }),
+        }
+        true
+    });
     let section_display = move || {
-        let Some(section_segments) = section_resource.read(cx) else {
+        let Some(cues) = section_resource.read(cx) else {
             return Ok::<_, ServerFnError>("\u{200b}".to_string());
         };
-        let section_string = section_segments_to_string(&section_segments?);
+        let cues = cues?;
+        let section_string = cues_to_string(&cues.section, &cues.next);
         if section_string.is_empty() {
             // Zero-width space so that the vertical space is reserved when not displaying anything
             Ok("\u{200b}".to_string())
@@ -167,144 +976,761 @@ fn Director(cx: Scope) -> impl IntoView {
             Ok(section_string)
         }
     };
-
-    cfg_if! {
-        if #[cfg(not(feature = "ssr"))] {
-            use leptos_dom::helpers::location;
-            use leptos::spawn_local;
-            use futures::StreamExt;
-
-            let socket_stream_result = location().host().map_err(|_| SectionLoadError::LocationHostError)
-                .and_then(|host| {
-                    gloo_net::websocket::futures::WebSocket::open(&format!("ws://{}/ws", host))
-                        .map_err(|err| SectionLoadError::WebSocketOpenError(err.to_string()))
-                    });
-            match socket_stream_result {
-                Ok(mut socket_stream) =>
-                    spawn_local(async move {
-                        loop {
-                            match socket_stream.next().await {
-                                Some(Ok(gloo_net::websocket::Message::Text(message))) => {
-                                    let mut message_chars = message.chars();
-                                    let section_type = message_chars.next();
-                                    let section_number = message_chars.as_str().parse::<NonZeroUsize>().ok();
-                                    section_resource.set(Ok((section_type, section_number)));
-                                },
-                                Some(Err(err)) => {
-                                    warn!("Error receiving message from WebSocket: {}", err);
-                                    break;
-                                }
-                                _ => {}
-                            }
+    let history_display = move || {
+        let Some(Ok(history)) = history_resource.read(cx) else {
+            return None;
+        };
+        let entries = history
+            .into_iter()
+            .map(|entry| {
+                let time = entry.timestamp.with_timezone(&Local).format("%H:%M:%S").to_string();
+                view! { cx,
+                    <li>
+                        <span class="history-time">{time}</span>
+                        " "
+                        {section_segments_to_string(&entry.previous)}
+                        " ⇒ "
+                        {section_segments_to_string(&entry.section)}
+                    </li>
+                }
+            })
+            .collect_view(cx);
+        Some(view! { cx,
+            <ol class="history-entries">{entries}</ol>
+            <form class="history-export" action="/export" method="get">
+                <input type="hidden" name="room" value=room.get_value()/>
+                <label>"From " <input type="datetime-local" name="from"/></label>
+                <label>"To " <input type="datetime-local" name="to"/></label>
+                <select name="format">
+                    <option value="csv">"Cue log (CSV)"</option>
+                    <option value="songs-csv">"Song sections (CSV)"</option>
+                    <option value="json">"Cue log and song sections (JSON)"</option>
+                </select>
+                <input type="submit" value="Download"/>
+            </form>
+        })
+    };
+    let setlist_display = move || {
+        let Some(Ok((setlist, position))) = setlist_resource.read(cx) else {
+            return None;
+        };
+        let current_song = position.map(|p| p.song);
+        let song_options = setlist
+            .songs
+            .iter()
+            .enumerate()
+            .map(|(index, song)| {
+                view! { cx,
+                    <option value=index.to_string() selected=current_song == Some(index)>{song.title.clone()}</option>
+                }
+            })
+            .collect_view(cx);
+        let arrangement = position
+            .and_then(|p| setlist.songs.get(p.song).map(|song| (p.step, song.arrangement.clone())))
+            .map(|(current_step, arrangement)| {
+                arrangement
+                    .iter()
+                    .enumerate()
+                    .map(|(step, section)| {
+                        view! { cx,
+                            <span class="arrangement-step" class:current={step == current_step}>
+                                {section_segments_to_string(section)}
+                            </span>
                         }
-                    }),
-                Err(err) => warn!("{}", err),
+                    })
+                    .collect_view(cx)
+            });
+
+        Some(view! { cx,
+            <select
+                class="setlist-songs"
+                on:change=move |ev| {
+                    if let Ok(song) = event_target_value(&ev).parse::<usize>() {
+                        select_song_action.dispatch(SelectSong { room: room.get_value(), song });
+                    }
+                }
+            >
+                <option value="" selected=current_song.is_none()>"Select song"</option>
+                {song_options}
+            </select>
+            <div class="arrangement">{arrangement}</div>
+        })
+    };
+
+    // Songs from the library, and buttons for the sections of the loaded one. Left out if the library is disabled.
+    let library_display = move || {
+        let Some(Ok(songs)) = library_resource.read(cx) else {
+            return None;
+        };
+        let loaded = loaded_song();
+        let loaded_id = loaded.as_ref().map(|song| song.id.clone());
+        let song_options = songs
+            .into_iter()
+            .map(|song| {
+                let selected = loaded_id.as_ref() == Some(&song.id);
+                view! { cx, <option value=song.id selected=selected>{song.title}</option> }
+            })
+            .collect_view(cx);
+        let song_details = loaded.map(|song| {
+            let facts = [song.key.map(|key| format!("Key {}", key)), song.tempo.map(|tempo| format!("{} BPM", tempo))]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(" · ");
+            let section_buttons = song
+                .sections
+                .into_iter()
+                .map(|section| {
+                    let cue = section.section;
+                    let current = move || matches!(section_resource.read(cx), Some(Ok(cues)) if cues.section == cue);
+                    view! { cx,
+                        <button class:current=current title=section.name on:click=move |_| update_section(&|section| *section = cue)>
+                            {section_segments_to_string(&cue)}
+                        </button>
+                    }
+                })
+                .collect_view(cx);
+            view! { cx,
+                <span class="song-facts">{facts}</span>
+                <div class="song-sections">{section_buttons}</div>
             }
-        }
-    }
+        });
+        Some(view! { cx,
+            <div class="library-song">
+                <select on:change=move |ev| {
+                    let id = event_target_value(&ev);
+                    load_song_action.dispatch(LoadLibrarySong { room: room.get_value(), id: (!id.is_empty()).then_some(id) });
+                }>
+                    <option value="" selected=loaded_id.is_none()>"No song loaded"</option>
+                    {song_options}
+                </select>
+                {song_details}
+            </div>
+        })
+    };
+
+    let director_buttons = move || {
+        let Some(Ok(vocabulary)) = vocabulary_resource.read(cx) else {
+            return None;
+        };
+        let cue_buttons = vocabulary
+            .cues
+            .iter()
+            .map(|cue| {
+                let code = cue.code;
+                // Positions are passed as variables so that the stylesheet can transpose them in portrait orientation
+                let mut style = String::new();
+                if let Some(row) = cue.row {
+                    style.push_str(&format!("--cue-row: {};", row));
+                }
+                if let Some(column) = cue.column {
+                    style.push_str(&format!("--cue-column: {};", column));
+                }
+                if let Some(color) = &cue.color {
+                    style.push_str(&format!("background-color: {};", color));
+                }
+                // Cues that the loaded song doesn't have are dimmed, but can still be used
+                let unused = move || loaded_song().is_some_and(|song| !song.uses_cue(code));
+                view! { cx,
+                    <button class="cue" class:unused=unused style=style title=cue.label.clone() on:click=move |_| change_section_type(code)>
+                        {code.to_string()}
+                    </button>
+                }
+            })
+            .collect_view(cx);
+        let audience_buttons = (!vocabulary.groups.is_empty()).then(|| {
+            let group_buttons = vocabulary
+                .groups
+                .iter()
+                .map(|group| {
+                    let group = store_value(cx, group.clone());
+                    view! { cx,
+                        <button
+                            class:active={move || audience.with(|audience| audience.as_ref() == Some(&group.get_value()))}
+                            on:click=move |_| set_audience.set(Some(group.get_value()))
+                        >
+                            {group.get_value()}
+                        </button>
+                    }
+                })
+                .collect_view(cx);
+            view! { cx,
+                <div class="audience-selector">
+                    <button class:active={move || audience.with(Option::is_none)} on:click=move |_| set_audience.set(None)>
+                        "Everyone"
+                    </button>
+                    {group_buttons}
+                </div>
+            }
+        });
+        let vocabulary = store_value(cx, vocabulary);
+        let number_buttons = (1..=vocabulary.with_value(Vocabulary::max_number))
+            .filter_map(NonZeroUsize::new)
+            .map(|number| {
+                let disabled = move || !vocabulary.with_value(|vocabulary| number_allowed(vocabulary, number));
+                view! { cx,
+                    <button class="number" disabled=disabled on:click=move |_| set_section_number(Some(number))>
+                        {number.to_string()}
+                    </button>
+                }
+            })
+            .collect_view(cx);
+
+        Some(view! { cx,
+            {audience_buttons}
+            <div class="director-buttons" style=format!("--director-columns: {}", vocabulary.with_value(|v| v.columns))>
+                {cue_buttons}
+                {number_buttons}
+                <button class="clear" on:click=move |_| clear_section()>"-"</button>
+            </div>
+        })
+    };
+
+    // Devices connected to the room, kept up to date by the server
+    let (devices, set_devices) = create_signal(cx, Vec::<Device>::new());
+    let devices_display = move || {
+        devices.with(|devices| {
+            devices
+                .iter()
+                .map(|device| {
+                    let time = device.last_seen.with_timezone(&Local).format("%H:%M:%S").to_string();
+                    view! { cx,
+                        <li class:stale={device.stale}>
+                            <span class="device-name">{device.name.clone()}</span>
+                            " "
+                            <span class="device-role">{device.role.clone()}</span>
+                            " "
+                            <span class="device-last-seen">{time}</span>
+                            {device.stale.then_some(" ⚠ not responding")}
+                        </li>
+                    }
+                })
+                .collect_view(cx)
+        })
+    };
+    // Which of the musicians' devices have acknowledged the current cue
+    let ack_display = move || {
+        let Some(Ok(cues)) = section_resource.read(cx) else {
+            return None;
+        };
+        let everyone = TargetedCue {
+            section: cues.section,
+            sequence: cues.cue_sequence,
+        };
+        let devices = devices.get();
+        targeted.with(|targeted| {
+            // Each musician acknowledges the cue shown for their groups
+            let musicians: Vec<(&Device, TargetedCue)> = devices
+                .iter()
+                .filter(|device| device.role != DIRECTOR_ROLE)
+                .map(|device| (device, cue_for_groups(everyone, targeted, &device.groups)))
+                .filter(|(_, cue)| cue.section.0.is_some())
+                .collect();
+            if musicians.is_empty() {
+                return None;
+            }
+            let waiting: Vec<&str> = musicians
+                .iter()
+                .filter(|(device, cue)| device.acked != Some(cue.sequence))
+                .map(|(device, _)| device.name.as_str())
+                .collect();
+            let status = format!("✓ {}/{}", musicians.len() - waiting.len(), musicians.len());
+            Some(view! { cx,
+                <div class="ack-status" class:complete={waiting.is_empty()}>
+                    {status}
+                    {(!waiting.is_empty()).then(|| format!(" · waiting for {}", waiting.join(", ")))}
+                </div>
+            })
+        })
+    };
+
+    let key_legend = move || {
+        let Some(Ok(vocabulary)) = vocabulary_resource.read(cx) else {
+            return None;
+        };
+        let bindings = vocabulary.keys.bindings(&vocabulary.cues);
+        Some(view! { cx, <KeyLegend bindings=bindings cues=vocabulary.cues enabled=shortcuts set_enabled=set_shortcuts/> })
+    };
+
+    let targeted_display = move || {
+        targeted.with(|targeted| {
+            targeted
+                .iter()
+                .map(|(group, cue)| {
+                    let section = match section_segments_to_string(&cue.section) {
+                        section if section.is_empty() => "-".to_string(),
+                        section => section,
+                    };
+                    view! { cx, <span class="targeted-cue">{format!("{}: {}", group, section)}</span> }
+                })
+                .collect_view(cx)
+        })
+    };
+
+    let cue_socket = connect_cue_socket(
+        cx,
+        room.get_value(),
+        Registration {
+            name: None,
+            role: Some(DIRECTOR_ROLE.to_string()),
+            groups: None,
+        },
+        move |message| {
+            let mut cues = latest_cues.get_value();
+            let changed = message.apply_to(&mut cues);
+            latest_cues.set_value(cues);
+            if let (true, Some(cues)) = (changed, cues) {
+                section_resource.set(Ok(cues));
+                history_resource.refetch();
+            }
+            match message {
+                // The setlist isn't part of the messages, so fetch it separately. A snapshot means the socket has
+                // just (re)connected, so anything could have changed.
+                ServerMessage::Snapshot { .. } | ServerMessage::SetlistChanged { .. } => {
+                    setlist_resource.refetch();
+                    loaded_song_resource.refetch();
+                }
+                ServerMessage::Devices { devices } => set_devices.set(devices),
+                ServerMessage::Targeted { cues } => set_targeted.set(cues),
+                ServerMessage::Message { text } => set_notice.set(text),
+                ServerMessage::Error { message } => warn!("Server error: {}", message),
+                _ => {}
+            }
+        },
+    );
 
     view! { cx,
+        <ConnectionIndicator status=cue_socket.status/>
         <div class="director-container">
             <Suspense
                 fallback= move || view! { cx, <div class="section-display">"\u{200b}"</div>}
             >
                 <div class="section-display">{section_display}</div>
+                <div class="targeted-cues">{targeted_display}</div>
+                {ack_display}
             </Suspense>
-            <div class="director-buttons">
-                <button on:click=move |_| change_section_type('C')>"C"</button>
-                <button on:click=move |_| change_section_type('V')>"V"</button>
-                <button on:click=move |_| change_section_type('B')>"B"</button>
-                <button on:click=move |_| change_section_type('P')>"P"</button>
-                <button on:click=move |_| change_section_type('W')>"W"</button>
-                <button on:click=move |_| change_section_type('E')>"E"</button>
-                <button on:click=move |_| change_section_type('X')>"X"</button>
-                <button on:click=move |_| set_section_number(NonZeroUsize::new(1))>"1"</button>
-                <button on:click=move |_| set_section_number(NonZeroUsize::new(2))>"2"</button>
-                <button on:click=move |_| set_section_number(NonZeroUsize::new(3))>"3"</button>
-                <button on:click=move |_| set_section_number(NonZeroUsize::new(4))>"4"</button>
-                <button on:click=move |_| set_section_number(NonZeroUsize::new(5))>"5"</button>
-                <button on:click=move |_| change_section_type('R')>"R"</button>
-                <button on:click=move |_| clear_section()>"-"</button>
+            <div class="director-controls">
+                <button class:active=staging on:click=move |_| set_staging.update(|staging| *staging = !*staging)>
+                    "Stage next"
+                </button>
+                <button on:click=move |_| promote_next_section()>"Go"</button>
+                <button on:click=move |_| undo_section_action.dispatch(UndoSection { room: room.get_value() })>"Undo"</button>
+            </div>
+            <div class="director-navigation">
+                <button on:click=move |_| previous_section_action.dispatch(PreviousSection { room: room.get_value() })>"◀"</button>
+                <div class="setlist-status">
+                    <Suspense fallback=|| ()>{setlist_display}</Suspense>
+                    <A href=room_path(&room.get_value(), "setlist")>"Edit setlist"</A>
+                    " "
+                    <A href=room_path(&room.get_value(), "library")>"Song library"</A>
+                </div>
+                <button on:click=move |_| next_section_action.dispatch(NextSection { room: room.get_value() })>"▶"</button>
             </div>
+            <Suspense fallback=|| ()>{library_display}</Suspense>
+            <Suspense fallback=|| ()>{director_buttons}</Suspense>
+            <details class="director-history">
+                <summary>"History"</summary>
+                <Suspense fallback=|| ()>{history_display}</Suspense>
+            </details>
+            <details class="director-devices">
+                <summary>{move || format!("Devices ({})", devices.with(Vec::len))}</summary>
+                <ul class="device-list">{devices_display}</ul>
+            </details>
+            <details class="director-notice">
+                <summary>
+                    {move || notice.with(|notice| if notice.is_empty() { "Notice".to_string() } else { format!("Notice: {}", notice) })}
+                </summary>
+                <ActionForm action=send_notice_action>
+                    <input type="hidden" name="room" value=room.get_value()/>
+                    <input type="text" name="text" placeholder="Shown below the signal, e.g. Key change to A"/>
+                    <input type="submit" value="Send"/>
+                    <button
+                        type="button"
+                        disabled=move || notice.with(String::is_empty)
+                        on:click=move |_| send_notice_action.dispatch(SendNotice { room: room.get_value(), text: String::new() })
+                    >
+                        "Clear"
+                    </button>
+                </ActionForm>
+            </details>
+            <details class="director-keys">
+                <summary>"Keyboard"</summary>
+                <Suspense fallback=|| ()>{key_legend}</Suspense>
+            </details>
         </div>
     }
 }
 
 #[component]
 fn SectionDisplay(cx: Scope) -> impl IntoView {
+    let room = store_value(cx, use_room(cx));
+    let query = use_query_map(cx).get_untracked();
+    // `?label=long` shows e.g. "Verse 2" instead of "V2"
+    let long_label = query.get("label").map(String::as_str) == Some("long");
+    // `?status=hide` hides the connection status, e.g. for an OBS browser source
+    let show_status = query.get("status").map(String::as_str) != Some("hide");
+    // `?groups=Band,Drums` adds the cues targeted at those groups to the cues for everyone
+    let groups = store_value(cx, query.get("groups").map(|groups| parse_groups(groups)).unwrap_or_default());
+    // `?name=...&role=...` is how the display is listed on the director's page, e.g. `?name=Tablet&role=Drums`
+    let device = Registration {
+        name: query.get("name").cloned(),
+        role: Some(query.get("role").cloned().unwrap_or_else(|| "Display".to_string())),
+        groups: query.get("groups").cloned(),
+    };
+    // Newest cues from any source, so that a server function response overtaken by the socket is ignored
+    let latest_cues = store_value(cx, None::<Cues>);
     let section_resource = create_resource(
         cx,
         || (),
         move |_| async move {
-            Ok::<_, SectionLoadError>(section_segments_to_string(
-                &get_section(cx)
-                    .await
-                    .map_err(|err| SectionLoadError::from(ServerFnErrorErr::from(err)))?,
-            ))
+            let cues = get_section(cx, room.get_value(), groups.get_value())
+                .await
+                .map_err(|err| SectionLoadError::from(ServerFnErrorErr::from(err)))?;
+            latest_cues.update_value(|latest| {
+                cues.merge_into(latest);
+            });
+            Ok::<_, SectionLoadError>(latest_cues.get_value().unwrap_or(cues))
         },
     );
-    cfg_if! {
-        if #[cfg(not(feature = "ssr"))] {
-            use leptos_dom::helpers::location;
-            use leptos::spawn_local;
-            use futures::StreamExt;
-
-            let socket_stream_result = location().host().map_err(|_| SectionLoadError::LocationHostError)
-                .and_then(|host| {
-                    gloo_net::websocket::futures::WebSocket::open(&format!("ws://{}/ws", host))
-                        .map_err(|err| SectionLoadError::WebSocketOpenError(err.to_string()))
-                    });
-            match socket_stream_result {
-                Ok(mut socket_stream) =>
-                    spawn_local(async move {
-                        loop {
-                            match socket_stream.next().await {
-                                Some(Ok(gloo_net::websocket::Message::Text(message))) => section_resource.set(Ok(message)),
-                                Some(Err(err)) => {
-                                    section_resource.set(Err(SectionLoadError::WebSocketError(err.to_string())));
-                                    break;
-                                }
-                                _ => {}
-                            }
+    let vocabulary_resource = create_resource(cx, || (), move |_| get_vocabulary(cx));
+    // `?profile=stream` styles the page as configured on the server, and `?size=6rem&color=white` and the like
+    // override single properties
+    let query_style = store_value(cx, DisplayStyle::from_query(&query));
+    let profile = store_value(cx, query.get("profile").cloned());
+    let profile_resource = create_resource(
+        cx,
+        || (),
+        move |_| async move {
+            match profile.get_value() {
+                Some(name) => get_display_profile(cx, name).await.map(Some),
+                None => Ok(None),
+            }
+        },
+    );
+    let display_style = move || {
+        let profile = match profile_resource.read(cx) {
+            Some(Ok(Some(profile))) => profile,
+            Some(Err(err)) => {
+                warn!("{}", err);
+                DisplayStyle::default()
+            }
+            _ => DisplayStyle::default(),
+        };
+        profile.overridden_by(query_style.get_value())
+    };
+    let page_style = move || display_style().css_variables();
+    let label = move |section: &SectionTuple| match vocabulary_resource.read(cx) {
+        Some(Ok(vocabulary)) if long_label => vocabulary.label(section),
+        _ => section_segments_to_string(section),
+    };
+    let section_view = move || {
+        section_resource.read(cx).map(|cues| {
+            cues.map(|cues| {
+                let next_view = cues.next.0.is_some().then(|| {
+                    view! { cx,
+                        <span class="next-separator">"→"</span>
+                        <span class="next-section">{label(&cues.next)}</span>
+                    }
+                });
+                view! { cx,
+                    <span class="current-section">{label(&cues.section)}</span>
+                    {next_view}
+                }
+            })
+        })
+    };
+    // Text sent along with the cues by the server
+    let (notice, set_notice) = create_signal(cx, String::new());
+    let cue_socket = connect_cue_socket(
+        cx,
+        room.get_value(),
+        device,
+        move |message| {
+            let mut cues = latest_cues.get_value();
+            let changed = message.apply_to(&mut cues);
+            latest_cues.set_value(cues);
+            if let (true, Some(cues)) = (changed, cues) {
+                section_resource.set(Ok(cues));
+            }
+            if let ServerMessage::Message { text } = message {
+                set_notice.set(text);
+            }
+        },
+    );
+
+    // The effect and sound of the style when the cue changes, but not for the cue shown on loading the page
+    let (effect, set_effect) = create_signal(cx, None::<ChangeEffect>);
+    let shown_sequence = store_value(cx, None::<u64>);
+    // Counts the effects, so that the end of an earlier one doesn't cut a later one short
+    let effects_started = store_value(cx, 0u64);
+    create_effect(cx, move |_| {
+        let Some(Ok(cues)) = section_resource.read(cx) else {
+            return;
+        };
+        let previous = shown_sequence.get_value();
+        shown_sequence.set_value(Some(cues.cue_sequence));
+        if previous.is_none() || previous == Some(cues.cue_sequence) || cues.section.0.is_none() {
+            return;
+        }
+        let style = display_style();
+        if let Some(change_effect) = style.effect {
+            effects_started.update_value(|started| *started += 1);
+            let started = effects_started.get_value();
+            set_effect.set(Some(change_effect));
+            if let Some(duration) = style.effect_duration() {
+                set_timeout(
+                    move || {
+                        if effects_started.try_get_value() == Some(started) {
+                            set_effect.try_set(None);
                         }
-                    }),
-                Err(err) => section_resource.set(Err(err)),
+                    },
+                    duration,
+                );
             }
         }
-    }
+        let sound = style.sound(&cues.section);
+        if sound.is_some() || style.chime == Some(true) {
+            play_cue_sound(sound);
+        }
+    });
+    let page_class = move || match effect.get() {
+        Some(effect) => format!("display-page {}", effect.class()),
+        None => "display-page".to_string(),
+    };
+
+    // Tapping the display acknowledges the current cue
+    let (acked, set_acked) = create_signal(cx, None::<u64>);
+    let acknowledge = move |_| {
+        unlock_audio();
+        set_effect.set(None);
+        if let Some(cues) = latest_cues.get_value() {
+            cue_socket.send(ClientMessage::Ack {
+                sequence: cues.cue_sequence,
+            });
+            set_acked.set(Some(cues.cue_sequence));
+        }
+    };
+    let is_acked = move || {
+        acked.get().is_some_and(|acked| {
+            matches!(
+                section_resource.read(cx),
+                Some(Ok(cues)) if acked == cues.cue_sequence && cues.section.0.is_some()
+            )
+        })
+    };
 
     view! { cx,
         <Title text="Song Director - View" />
-        <Suspense
-            fallback= move || view! { cx, <div class="section-display">"\u{200b}"</div>}
-        >
-            <ErrorBoundary
-                fallback= move |cx, errors| {
-                    let errors: Vec<leptos::error::Error> = errors()
-                        .into_iter()
-                        .map(|(_, v)| v)
-                        .collect();
-                    view! { cx,
-                        <h1>{if errors.len() > 1 {"Errors"} else {"Error"}}</h1>
-                        <For
-                            // a function that returns the items we're iterating over; a signal is fine
-                            each= move || {errors.clone().into_iter().enumerate()}
-                            // a unique key for each item as a reference
-                            key=|(index, _error)| *index
-                            // renders each item to a view
-                            view= move |cx, error| {
-                                let error_string = error.1.to_string();
-                                view! {
-                                    cx,
-                                    <p>{error_string}</p>
+        {show_status.then(|| view! { cx, <ConnectionIndicator status=cue_socket.status/> })}
+        <div class=page_class style=page_style on:click=acknowledge>
+            <Suspense
+                fallback= move || view! { cx, <div class="section-display">"\u{200b}"</div>}
+            >
+                <ErrorBoundary
+                    fallback= move |cx, errors| {
+                        let errors: Vec<leptos::error::Error> = errors()
+                            .into_iter()
+                            .map(|(_, v)| v)
+                            .collect();
+                        view! { cx,
+                            <h1>{if errors.len() > 1 {"Errors"} else {"Error"}}</h1>
+                            <For
+                                // a function that returns the items we're iterating over; a signal is fine
+                                each= move || {errors.clone().into_iter().enumerate()}
+                                // a unique key for each item as a reference
+                                key=|(index, _error)| *index
+                                // renders each item to a view
+                                view= move |cx, error| {
+                                    let error_string = error.1.to_string();
+                                    view! {
+                                        cx,
+                                        <p>{error_string}</p>
+                                    }
                                 }
-                            }
-                        />
+                            />
+                        }
                     }
+                >
+                    <div class="section-display">{section_view}</div>
+                </ErrorBoundary>
+            </Suspense>
+            <Show when=move || notice.with(|notice| !notice.is_empty()) fallback=|_| ()>
+                <div class="display-notice">{notice}</div>
+            </Show>
+            <Show when=is_acked fallback=|_| ()>
+                <div class="ack-confirmation">"✓ Acknowledged"</div>
+            </Show>
+        </div>
+    }
+}
+
+#[component]
+fn SetlistEditor(cx: Scope) -> impl IntoView {
+    let room = use_room(cx);
+    let director_path = room_path(&room, "");
+    let setlist_room = room.clone();
+    let setlist_resource = create_resource(cx, move || setlist_room.clone(), move |room| get_setlist(cx, room));
+    let set_setlist_action = create_server_action::<SetSetlist>(cx);
+    let setlist_text = move || {
+        setlist_resource
+            .read(cx)
+            .and_then(Result::ok)
+            .map(|(setlist, _)| setlist.to_text())
+    };
+    let save_status = move || {
+        set_setlist_action.value().get().map(|result| match result {
+            Ok(()) => "Saved".to_string(),
+            Err(err) => err.to_string(),
+        })
+    };
+
+    view! { cx,
+        <Title text="Song Director - Setlist" />
+        <div class="setlist-editor">
+            <h1>"Setlist"</h1>
+            <p>"One song per line, with the song's arrangement after a colon, e.g. " <code>"Amazing Grace: V1 C V2 C B C E"</code></p>
+            <ActionForm action=set_setlist_action>
+                <input type="hidden" name="room" value=room/>
+                <Suspense fallback=|| ()>
+                    <textarea name="setlist" rows="15">{setlist_text}</textarea>
+                </Suspense>
+                <div>
+                    <input type="submit" value="Save"/>
+                    " "
+                    <span class="save-status">{save_status}</span>
+                </div>
+            </ActionForm>
+            <A href=director_path>"Back to director"</A>
+        </div>
+    }
+}
+
+#[component]
+fn LibraryPage(cx: Scope) -> impl IntoView {
+    let room = use_room(cx);
+    let director_path = room_path(&room, "");
+    let room = store_value(cx, room);
+    let import_action = create_server_action::<ImportSong>(cx);
+    let delete_action = create_server_action::<DeleteLibrarySong>(cx);
+    let load_action = create_server_action::<LoadLibrarySong>(cx);
+    let library_resource = create_resource(
+        cx,
+        move || (import_action.version().get(), delete_action.version().get()),
+        move |_| get_library(cx),
+    );
+    let import_status = move || {
+        import_action.value().get().map(|result| match result {
+            Ok(song) => format!("Imported {}", song.title),
+            Err(err) => err.to_string(),
+        })
+    };
+    let load_status = move || {
+        load_action.value().get().map(|result| match result {
+            Ok(()) => format!("Loaded into room {}", room.get_value()),
+            Err(err) => err.to_string(),
+        })
+    };
+    let songs_display = move || {
+        library_resource.read(cx).map(|songs| match songs {
+            Ok(songs) => {
+                let rows = songs
+                    .into_iter()
+                    .map(|song| {
+                        let sections = song
+                            .sections
+                            .iter()
+                            .map(|section| section_segments_to_string(&section.section))
+                            .collect::<Vec<_>>()
+                            .join(" ");
+                        let setlist_line = song.setlist_line();
+                        let id = store_value(cx, song.id);
+                        view! { cx,
+                            <tr>
+                                <td>{song.title}</td>
+                                <td>{song.key}</td>
+                                <td>{song.tempo.map(|tempo| tempo.to_string())}</td>
+                                <td>{sections}</td>
+                                <td><code>{setlist_line}</code></td>
+                                <td>
+                                    <button on:click=move |_| {
+                                        load_action.dispatch(LoadLibrarySong {
+                                            room: room.get_value(),
+                                            id: Some(id.get_value()),
+                                        })
+                                    }>"Load"</button>
+                                    " "
+                                    <button on:click=move |_| delete_action.dispatch(DeleteLibrarySong { id: id.get_value() })>
+                                        "Delete"
+                                    </button>
+                                </td>
+                            </tr>
+                        }
+                    })
+                    .collect_view(cx);
+                view! { cx,
+                    <table class="library-songs">
+                        <tr><th>"Title"</th><th>"Key"</th><th>"Tempo"</th><th>"Sections"</th><th>"Setlist line"</th><th></th></tr>
+                        {rows}
+                    </table>
                 }
-            >
-                <div class="section-display">{move || section_resource.read(cx)}</div>
-            </ErrorBoundary>
-        </Suspense>
+                .into_view(cx)
+            }
+            Err(err) => view! { cx, <p class="library-error">{err.to_string()}</p> }.into_view(cx),
+        })
+    };
+
+    view! { cx,
+        <Title text="Song Director - Library" />
+        <div class="library-page">
+            <h1>"Song library"</h1>
+            <Suspense fallback=|| ()>{songs_display}</Suspense>
+            <p class="save-status">{load_status}</p>
+            <h2>"Import"</h2>
+            <p>
+                "Paste a chart in ChordPro format. Section blocks such as " <code>"{start_of_verse}"</code> " and "
+                <code>"{soc}"</code> ", and comments naming a cue such as " <code>"{c: Bridge}"</code>
+                ", become the song's sections. Importing a song with the same title replaces it."
+            </p>
+            <ActionForm action=import_action>
+                <textarea name="chart" rows="15"></textarea>
+                <div>
+                    <input type="submit" value="Import"/>
+                    " "
+                    <span class="save-status">{import_status}</span>
+                </div>
+            </ActionForm>
+            <A href=director_path>"Back to director"</A>
+        </div>
+    }
+}
+
+#[component]
+fn LoginPage(cx: Scope) -> impl IntoView {
+    let next = use_query_map(cx)
+        .get_untracked()
+        .get("next")
+        .cloned()
+        .unwrap_or_else(|| "/".to_string());
+    let login_action = create_server_action::<DirectorLogin>(cx);
+    let login_error = move || {
+        login_action
+            .value()
+            .get()
+            .and_then(Result::err)
+            .map(|err| err.to_string())
+    };
+
+    view! { cx,
+        <Title text="Song Director - Login" />
+        <div class="login-page">
+            <h1>"Director login"</h1>
+            <ActionForm action=login_action>
+                <input type="hidden" name="next" value=next/>
+                <label>"PIN " <input type="password" name="pin" autofocus=true/></label>
+                <label>"Your name " <input type="text" name="name" placeholder="Optional"/></label>
+                <input type="submit" value="Log in"/>
+                <p class="login-error">{login_error}</p>
+            </ActionForm>
+        </div>
     }
 }
@@ -0,0 +1,31 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::app::SectionTuple;
+
+/// A cue and the number of the update that set it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TargetedCue {
+    pub section: SectionTuple,
+    pub sequence: u64,
+}
+
+/// The cue shown to a display in `groups`: whichever was set last of the cue for everyone and the cues targeted at
+/// its groups
+pub fn cue_for_groups(everyone: TargetedCue, targeted: &BTreeMap<String, TargetedCue>, groups: &[String]) -> TargetedCue {
+    groups
+        .iter()
+        .filter_map(|group| targeted.get(group))
+        .fold(everyone, |latest, cue| if cue.sequence > latest.sequence { *cue } else { latest })
+}
+
+/// Splits a comma-separated list of groups, e.g. `Band,Drums`
+pub fn parse_groups(groups: &str) -> Vec<String> {
+    groups
+        .split(',')
+        .map(str::trim)
+        .filter(|group| !group.is_empty())
+        .map(str::to_string)
+        .collect()
+}
@@ -0,0 +1,213 @@
+use cfg_if::cfg_if;
+
+/// Pages that anyone can open without logging in as a director. Everything else is director-only.
+pub fn is_public_page(path: &str) -> bool {
+    match path {
+        "/login" | "/view" => true,
+        _ => path
+            .strip_prefix("/room/")
+            .and_then(|path| path.strip_suffix("/view"))
+            .is_some_and(|room| !room.contains('/')),
+    }
+}
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use axum::extract::State;
+    use axum::middleware::Next;
+    use axum::response::{IntoResponse, Redirect, Response};
+    use http::{header, HeaderMap, Request};
+    use rand::RngCore;
+    use thiserror::Error;
+
+    use crate::config::AuthConfig;
+
+    /// Name of the cookie holding the session token
+    pub const SESSION_COOKIE: &str = "director_session";
+
+    #[derive(Debug, Error)]
+    pub enum AuthError {
+        #[error("wrong PIN")]
+        WrongPin,
+        #[error("too many failed attempts, try again in {0} seconds")]
+        TooManyAttempts(u64),
+        #[error("no director PIN is configured")]
+        Disabled,
+    }
+
+    struct Session {
+        name: String,
+        expires: Instant,
+    }
+
+    /// Failed logins from one address since its last successful login or lockout
+    struct FailedLogins {
+        count: u32,
+        last_failure: Instant,
+        locked_until: Option<Instant>,
+    }
+
+    impl FailedLogins {
+        /// Whether the address is neither locked out nor has failed recently, so the failures can be forgotten
+        fn has_expired(&self, now: Instant, window: Duration) -> bool {
+            match self.locked_until {
+                Some(locked_until) => locked_until <= now,
+                None => now.duration_since(self.last_failure) >= window,
+            }
+        }
+    }
+
+    /// Director logins. Viewers never need to log in. If no PIN is configured, everyone is a director.
+    pub struct Auth {
+        config: AuthConfig,
+        /// Whether the server is only reachable over HTTPS, so cookies can be restricted to it
+        secure: bool,
+        sessions: Mutex<HashMap<String, Session>>,
+        failed_logins: Mutex<HashMap<IpAddr, FailedLogins>>,
+    }
+
+    impl Auth {
+        pub fn new(config: AuthConfig, secure: bool) -> Self {
+            Auth {
+                config,
+                secure,
+                sessions: Mutex::new(HashMap::new()),
+                failed_logins: Mutex::new(HashMap::new()),
+            }
+        }
+
+        pub fn is_enabled(&self) -> bool {
+            self.config.pin.is_some()
+        }
+
+        /// Checks `pin` and starts a session for the director called `name`, returning its token.
+        /// Addresses with too many failed attempts are locked out for a while, even if the PIN is right.
+        pub fn login(&self, addr: IpAddr, pin: &str, name: &str) -> Result<String, AuthError> {
+            let Some(expected_pin) = &self.config.pin else {
+                return Err(AuthError::Disabled);
+            };
+
+            let mut failed_logins = self.failed_logins.lock().expect("failed login mutex poisoned");
+            let now = Instant::now();
+            // Failures are forgotten when a lockout ends, or after as long as a lockout without another failure
+            let window = Duration::from_secs(self.config.lockout);
+            failed_logins.retain(|_, failed| !failed.has_expired(now, window));
+            if let Some(locked_until) = failed_logins.get(&addr).and_then(|failed| failed.locked_until) {
+                return Err(AuthError::TooManyAttempts((locked_until - now).as_secs() + 1));
+            }
+
+            if !constant_time_eq(pin.as_bytes(), expected_pin.as_bytes()) {
+                let failed = failed_logins.entry(addr).or_insert(FailedLogins {
+                    count: 0,
+                    last_failure: now,
+                    locked_until: None,
+                });
+                failed.count += 1;
+                failed.last_failure = now;
+                if failed.count >= self.config.max_failures {
+                    failed.locked_until = Some(now + Duration::from_secs(self.config.lockout));
+                }
+                return Err(AuthError::WrongPin);
+            }
+            failed_logins.remove(&addr);
+            drop(failed_logins);
+
+            let mut token = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut token);
+            let token: String = token.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+            let mut sessions = self.sessions.lock().expect("session mutex poisoned");
+            sessions.retain(|_, session| session.expires > now);
+            sessions.insert(
+                token.clone(),
+                Session {
+                    name: name.to_string(),
+                    expires: now + Duration::from_secs(self.config.session_timeout),
+                },
+            );
+            Ok(token)
+        }
+
+        /// Name of the logged in director making the request, or `None` for viewers.
+        /// Without a PIN, every request is from an anonymous director.
+        pub fn director(&self, headers: &HeaderMap) -> Option<String> {
+            if !self.is_enabled() {
+                return Some("anonymous".to_string());
+            }
+            let token = session_token(headers)?;
+            let mut sessions = self.sessions.lock().expect("session mutex poisoned");
+            let now = Instant::now();
+            sessions.retain(|_, session| session.expires > now);
+            sessions.get(token).map(|session| session.name.clone())
+        }
+
+        /// `Set-Cookie` value for a new session
+        pub fn session_cookie(&self, token: &str) -> String {
+            format!(
+                "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}{}",
+                SESSION_COOKIE,
+                token,
+                self.config.session_timeout,
+                if self.secure { "; Secure" } else { "" }
+            )
+        }
+    }
+
+    /// Name a director logs in with, shown in the audit log
+    pub fn login_name(name: &str) -> String {
+        match name.trim() {
+            "" => "director".to_string(),
+            name => name.chars().take(64).collect(),
+        }
+    }
+
+    /// Token from an `Authorization: Bearer` header, as sent by API clients, or else from the session cookie
+    fn session_token(headers: &HeaderMap) -> Option<&str> {
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        bearer.or_else(|| session_cookie(headers))
+    }
+
+    fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|cookies| cookies.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(name, _)| *name == SESSION_COOKIE)
+            .map(|(_, token)| token)
+    }
+
+    /// Compares without exiting early, so that response times don't reveal how much of the PIN was right
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    /// Path to return to after logging in. Only local paths are allowed, so the login page can't be used to
+    /// redirect elsewhere.
+    pub fn login_redirect_target(next: &str) -> &str {
+        if next.starts_with('/') && !next.starts_with("//") && !next.contains('\\') {
+            next
+        } else {
+            "/"
+        }
+    }
+
+    /// Middleware sending viewers to the login page when they open anything other than a public page
+    pub async fn require_director<B>(State(auth): State<Arc<Auth>>, request: Request<B>, next: Next<B>) -> Response {
+        if is_public_page(request.uri().path()) || auth.director(request.headers()).is_some() {
+            return next.run(request).await;
+        }
+        let target = request.uri().path_and_query().map_or("/", |target| target.as_str());
+        // Escape what would otherwise end the `next` parameter early
+        let target = target.replace('%', "%25").replace('&', "%26").replace('#', "%23").replace('+', "%2B");
+        Redirect::to(&format!("/login?next={}", target)).into_response()
+    }
+}}
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::app::SectionTuple;
+use crate::library::{song_id, LibrarySong, SongSection};
+use crate::vocabulary::{Vocabulary, VocabularyError};
+
+#[derive(Clone, Debug, Error, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChordProError {
+    #[error("the chart has no {{title}}")]
+    MissingTitle,
+    #[error("line {0}: invalid tempo \"{1}\"")]
+    InvalidTempo(usize, String),
+    #[error("line {0}: no cue in the vocabulary is called \"{1}\"")]
+    UnknownSection(usize, String),
+    #[error("line {0}: {1}")]
+    Vocabulary(usize, VocabularyError),
+}
+
+/// Blocks that hold something other than a section of the song
+const NON_SECTION_BLOCKS: [&str; 6] = ["tab", "grid", "abc", "ly", "svg", "textblock"];
+
+/// The start of a section in the chart, numbered once the whole chart has been read
+struct Heading {
+    line: usize,
+    code: char,
+    number: Option<NonZeroUsize>,
+    /// Name given in the chart
+    name: Option<String>,
+}
+
+impl LibrarySong {
+    /// Reads a ChordPro chart. `{start_of_verse}`, `{soc}` and the other section blocks, and comments naming a cue
+    /// such as `{c: Verse 2}` or `{comment: Pre-Chorus}`, start a section. Sections are matched to the cues by
+    /// their labels in the vocabulary, and sections of the same kind without a number are numbered in order if
+    /// there are several of them. A comment right before a section block names the block instead of starting a
+    /// section of its own. `{chorus}` repeats the last chorus.
+    pub fn from_chordpro(text: &str, vocabulary: &Vocabulary) -> Result<Self, ChordProError> {
+        let mut title = None;
+        let mut key = None;
+        let mut tempo = None;
+        let mut headings: Vec<Heading> = Vec::new();
+        // Indices into `headings`
+        let mut arrangement: Vec<usize> = Vec::new();
+        let mut last_chorus = None;
+        let mut in_block = false;
+        // Index into `headings` of a comment heading on the line before
+        let mut comment_heading = None;
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let comment_before = comment_heading.take();
+            let Some((name, value)) = directive(line) else {
+                continue;
+            };
+            let (name, value) = match name.as_str() {
+                // `{meta: key G}` is the same as `{key: G}`
+                "meta" => match value.split_once(char::is_whitespace) {
+                    Some((name, value)) => (name.to_lowercase(), value.trim()),
+                    None => continue,
+                },
+                _ => (name, value),
+            };
+            match name.as_str() {
+                "title" | "t" => {
+                    title.get_or_insert_with(|| value.to_string());
+                }
+                "key" => {
+                    key.get_or_insert_with(|| value.to_string());
+                }
+                "tempo" => {
+                    let bpm = value.split_whitespace().next().and_then(|bpm| bpm.parse().ok());
+                    tempo = Some(bpm.ok_or_else(|| ChordProError::InvalidTempo(line_number, value.to_string()))?);
+                }
+                "comment" | "c" | "comment_italic" | "ci" | "comment_box" | "cb" | "highlight" if !in_block => {
+                    if let Some((code, number)) = cue_named(value, vocabulary) {
+                        comment_heading = Some(headings.len());
+                        arrangement.push(headings.len());
+                        headings.push(Heading {
+                            line: line_number,
+                            code,
+                            number,
+                            name: Some(value.trim_end_matches(':').trim().to_string()),
+                        });
+                    }
+                }
+                "chorus" => arrangement.extend(last_chorus),
+                name if name.starts_with("end_of_") || matches!(name, "eov" | "eoc" | "eob" | "eot" | "eog") => {
+                    in_block = false;
+                }
+                name => {
+                    let kind = match name {
+                        "sov" => "verse",
+                        "soc" => "chorus",
+                        "sob" => "bridge",
+                        "sot" | "sog" => continue,
+                        name => match name.strip_prefix("start_of_") {
+                            Some(kind) if !NON_SECTION_BLOCKS.contains(&kind) => kind,
+                            _ => continue,
+                        },
+                    };
+                    in_block = true;
+                    let comment = comment_before.and_then(|_| {
+                        arrangement.pop();
+                        headings.pop()?.name
+                    });
+                    let label = match (block_label(value), &comment) {
+                        ("", Some(comment)) => comment.as_str(),
+                        (label, _) => label,
+                    };
+                    let (code, number) = cue_named(label, vocabulary)
+                        .or_else(|| cue_named(kind, vocabulary).map(|(code, _)| (code, None)))
+                        .ok_or_else(|| ChordProError::UnknownSection(line_number, kind.to_string()))?;
+                    if kind == "chorus" {
+                        last_chorus = Some(headings.len());
+                    }
+                    arrangement.push(headings.len());
+                    headings.push(Heading {
+                        line: line_number,
+                        code,
+                        number,
+                        name: (!label.is_empty()).then(|| label.to_string()),
+                    });
+                }
+            }
+        }
+
+        let title = title.filter(|title| !song_id(title).is_empty()).ok_or(ChordProError::MissingTitle)?;
+        let sections = number_sections(&headings, vocabulary)?;
+        let mut distinct: Vec<SongSection> = Vec::new();
+        for (heading, section) in headings.iter().zip(&sections) {
+            if !distinct.iter().any(|known| known.section == *section) {
+                distinct.push(SongSection {
+                    section: *section,
+                    name: heading.name.clone().unwrap_or_else(|| vocabulary.label(section)),
+                });
+            }
+        }
+        Ok(LibrarySong {
+            id: song_id(&title),
+            title,
+            key,
+            tempo,
+            sections: distinct,
+            arrangement: arrangement.into_iter().map(|heading| sections[heading]).collect(),
+        })
+    }
+}
+
+/// Gives a number to the headings without one whose cue is used more than once, counting every heading of the cue
+fn number_sections(headings: &[Heading], vocabulary: &Vocabulary) -> Result<Vec<SectionTuple>, ChordProError> {
+    let mut totals: HashMap<char, usize> = HashMap::new();
+    for heading in headings {
+        *totals.entry(heading.code).or_default() += 1;
+    }
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    headings
+        .iter()
+        .map(|heading| {
+            let count = counts.entry(heading.code).or_default();
+            *count += 1;
+            let numbered = totals[&heading.code] > 1 && vocabulary.cue(heading.code).is_some_and(|cue| cue.numbers > 0);
+            let number = heading.number.or_else(|| NonZeroUsize::new(*count).filter(|_| numbered));
+            let section = (Some(heading.code), number);
+            vocabulary
+                .validate(&section)
+                .map_err(|err| ChordProError::Vocabulary(heading.line, err))?;
+            Ok(section)
+        })
+        .collect()
+}
+
+/// Name in lower case and value of a directive line such as `{title: Amazing Grace}` or `{soc}`
+fn directive(line: &str) -> Option<(String, &str)> {
+    let inner = line.trim().strip_prefix('{')?.strip_suffix('}')?;
+    let (name, value) = inner
+        .split_once(|ch: char| ch == ':' || ch.is_whitespace())
+        .unwrap_or((inner, ""));
+    Some((name.trim().to_lowercase(), value.trim().trim_start_matches(':').trim()))
+}
+
+/// Label of a section block, given either as `{start_of_verse: Verse 2}` or `{start_of_verse label="Verse 2"}`
+fn block_label(value: &str) -> &str {
+    match value.strip_prefix("label=") {
+        Some(label) => label.trim_matches(|ch| ch == '"' || ch == '\''),
+        None => value,
+    }
+}
+
+/// The cue called `name` in the vocabulary and the number after its label, e.g. `Verse 2` or `pre-chorus:`
+fn cue_named(name: &str, vocabulary: &Vocabulary) -> Option<(char, Option<NonZeroUsize>)> {
+    let name = name.trim().trim_end_matches(':').trim_end();
+    let label = name.trim_end_matches(|ch: char| ch.is_ascii_digit());
+    let number = match &name[label.len()..] {
+        "" => None,
+        digits => Some(digits.parse().ok()?),
+    };
+    let label = comparable_label(label);
+    if label.is_empty() {
+        return None;
+    }
+    vocabulary
+        .cues
+        .iter()
+        .find(|cue| comparable_label(&cue.label) == label)
+        .map(|cue| (cue.code, number))
+}
+
+/// Only the letters and digits in lower case, so that `Pre-Chorus`, `prechorus` and `Pre chorus` are the same
+fn comparable_label(label: &str) -> String {
+    label.chars().filter(|ch| ch.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(text: &str) -> Result<LibrarySong, ChordProError> {
+        LibrarySong::from_chordpro(text, &Vocabulary::default())
+    }
+
+    #[test]
+    fn numbers_sections_used_more_than_once() {
+        let song = import(
+            "{title: Amazing Grace}\n\
+             {sov}\nAmazing grace\n{eov}\n\
+             {soc}\nMy chains are gone\n{eoc}\n\
+             {sov}\n'Twas grace\n{eov}\n\
+             {start_of_bridge}\nThe earth shall soon\n{end_of_bridge}\n",
+        )
+        .unwrap();
+        assert_eq!(song.id, "amazing-grace");
+        assert_eq!(song.setlist_line(), "Amazing Grace: V1 C V2 B");
+        let names: Vec<&str> = song.sections.iter().map(|section| section.name.as_str()).collect();
+        assert_eq!(names, ["Verse 1", "Chorus", "Verse 2", "Bridge"]);
+    }
+
+    #[test]
+    fn chorus_repeats_the_last_chorus() {
+        let song = import(
+            "{t: Song}\n\
+             {start_of_verse}\nOne\n{end_of_verse}\n\
+             {start_of_chorus}\nRefrain\n{end_of_chorus}\n\
+             {start_of_verse}\nTwo\n{end_of_verse}\n\
+             {chorus}\n",
+        )
+        .unwrap();
+        assert_eq!(song.setlist_line(), "Song: V1 C V2 C");
+        assert_eq!(song.sections.len(), 3);
+    }
+
+    #[test]
+    fn keeps_numbers_and_labels_of_blocks() {
+        let song = import(
+            "{title: Song}\n\
+             {start_of_verse label=\"Verse 3\"}\nThree\n{end_of_verse}\n\
+             {start_of_verse: Pre-Chorus}\nRising\n{end_of_verse}\n\
+             {start_of_chorus label='Chorus 2'}\nRefrain\n{end_of_chorus}\n",
+        )
+        .unwrap();
+        assert_eq!(song.setlist_line(), "Song: V3 P C2");
+        assert_eq!(song.sections[1].name, "Pre-Chorus");
+    }
+
+    #[test]
+    fn comments_name_sections() {
+        let song = import(
+            "{title: Song}\n\
+             {c: Verse}\nOne\n\
+             {comment: Slowly}\n\
+             {c: Chorus:}\nRefrain\n\
+             {c: Verse}\nTwo\n",
+        )
+        .unwrap();
+        assert_eq!(song.setlist_line(), "Song: V1 C V2");
+    }
+
+    #[test]
+    fn comment_before_a_block_names_it() {
+        let song = import(
+            "{title: Song}\n\
+             {c: Verse 2}\n\n{start_of_verse}\nTwo\n{end_of_verse}\n\
+             {c: Chorus}\n{soc}\nRefrain\n{eoc}\n\
+             {c: Bridge}\nBridge lyrics\n{soc}\nRefrain\n{eoc}\n",
+        )
+        .unwrap();
+        assert_eq!(song.setlist_line(), "Song: V2 C1 B C2");
+        assert_eq!(song.sections[0].name, "Verse 2");
+    }
+
+    #[test]
+    fn reads_metadata() {
+        let song = import("{title: Song}\n{meta: key G}\n{meta: tempo 72}\n{soc}\nRefrain\n{eoc}\n").unwrap();
+        assert_eq!(song.key.as_deref(), Some("G"));
+        assert_eq!(song.tempo, Some(72));
+
+        let song = import("{title: Song}\n{key: Bbm}\n{tempo: 120 bpm}\n").unwrap();
+        assert_eq!(song.key.as_deref(), Some("Bbm"));
+        assert_eq!(song.tempo, Some(120));
+        assert_eq!(import("{title: Song}\n{tempo: fast}\n"), Err(ChordProError::InvalidTempo(2, "fast".to_string())));
+    }
+
+    #[test]
+    fn rejects_unknown_sections() {
+        assert_eq!(
+            import("{title: Song}\n{start_of_refrain}\nRefrain\n{end_of_refrain}\n"),
+            Err(ChordProError::UnknownSection(2, "refrain".to_string()))
+        );
+        // Blocks that aren't sections are skipped
+        let song = import("{title: Song}\n{start_of_tab}\ne|---|\n{end_of_tab}\n{sov}\nOne\n{eov}\n").unwrap();
+        assert_eq!(song.setlist_line(), "Song: V");
+        assert_eq!(import("{soc}\nRefrain\n{eoc}\n"), Err(ChordProError::MissingTitle));
+    }
+}
@@ -0,0 +1,341 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::path::{Path, PathBuf};
+
+    use serde::Deserialize;
+    use thiserror::Error;
+
+    use crate::control::ControlAction;
+    use crate::display::DisplayProfiles;
+    #[cfg(feature = "midi")]
+    use crate::midi::{MidiNotification, MidiTrigger};
+    use crate::obs::ObsRule;
+    use crate::rooms::DEFAULT_ROOM;
+    use crate::vocabulary::{Vocabulary, VocabularyError};
+
+    /// Environment variable holding the path of the configuration file
+    pub const CONFIG_PATH_ENV: &str = "DIRECTOR_CONFIG";
+    const DEFAULT_CONFIG_PATH: &str = "director.toml";
+
+    #[derive(Debug, Error)]
+    pub enum ConfigError {
+        #[error("could not read {0}: {1}")]
+        Io(PathBuf, std::io::Error),
+        #[error("could not parse {0}: {1}")]
+        Parse(PathBuf, toml::de::Error),
+        #[error("invalid vocabulary in {0}: {1}")]
+        Vocabulary(PathBuf, VocabularyError),
+    }
+
+    /// Server configuration, read from a TOML file. Every section is optional.
+    #[derive(Clone, Debug, Default, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct Config {
+        pub persistence: PersistenceConfig,
+        pub library: LibraryConfig,
+        pub rooms: RoomsConfig,
+        pub vocabulary: Vocabulary,
+        /// Styles of the display page, by name
+        pub display_profiles: DisplayProfiles,
+        pub recorder: RecorderConfig,
+        pub auth: AuthConfig,
+        /// Serve HTTPS instead of HTTP if present
+        pub tls: Option<TlsConfig>,
+        /// OSC control and cue notifications if present
+        pub osc: Option<OscConfig>,
+        /// MIDI control and cue notifications if present
+        #[cfg(feature = "midi")]
+        pub midi: Option<MidiConfig>,
+        pub webhooks: WebhooksConfig,
+        /// OBS scene and source changes if present
+        pub obs: Option<ObsConfig>,
+    }
+
+    impl Config {
+        /// Loads the configuration file named by `DIRECTOR_CONFIG`, or `director.toml` in the working directory.
+        /// A missing default file gives the default configuration, but a missing file that was explicitly
+        /// named is an error.
+        pub fn load() -> Result<Self, ConfigError> {
+            match std::env::var_os(CONFIG_PATH_ENV) {
+                Some(path) => Self::load_from(Path::new(&path)),
+                None => {
+                    let path = Path::new(DEFAULT_CONFIG_PATH);
+                    if path.exists() {
+                        Self::load_from(path)
+                    } else {
+                        Ok(Self::default())
+                    }
+                }
+            }
+        }
+
+        pub fn load_from(path: &Path) -> Result<Self, ConfigError> {
+            let text = std::fs::read_to_string(path).map_err(|err| ConfigError::Io(path.to_owned(), err))?;
+            let config: Config = toml::from_str(&text).map_err(|err| ConfigError::Parse(path.to_owned(), err))?;
+            config.vocabulary.check().map_err(|err| ConfigError::Vocabulary(path.to_owned(), err))?;
+            let vocabulary = &config.vocabulary;
+            for (key, action, instead) in vocabulary.keys.overridden(&vocabulary.cues) {
+                log::warn!(
+                    "Key {} doesn't press {} because it is used for {}",
+                    key,
+                    action.description(&vocabulary.cues),
+                    instead.description(&vocabulary.cues)
+                );
+            }
+            Ok(config)
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct RoomsConfig {
+        /// Seconds without clients or activity after which a room is closed
+        pub idle_timeout: u64,
+    }
+
+    impl Default for RoomsConfig {
+        fn default() -> Self {
+            RoomsConfig { idle_timeout: 60 * 60 }
+        }
+    }
+
+    /// Log of every cue change, for exporting after a service
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct RecorderConfig {
+        pub enabled: bool,
+        pub path: PathBuf,
+    }
+
+    impl Default for RecorderConfig {
+        fn default() -> Self {
+            RecorderConfig {
+                enabled: true,
+                path: PathBuf::from("cue-log.jsonl"),
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct AuthConfig {
+        /// PIN or password required to use the director pages. Without one, anyone can direct.
+        pub pin: Option<String>,
+        /// Seconds a director stays logged in
+        pub session_timeout: u64,
+        /// Failed logins from one address before it is locked out
+        pub max_failures: u32,
+        /// Seconds an address is locked out for, and after which its failed logins are forgotten
+        pub lockout: u64,
+    }
+
+    impl Default for AuthConfig {
+        fn default() -> Self {
+            AuthConfig {
+                pin: None,
+                session_timeout: 12 * 60 * 60,
+                max_failures: 5,
+                lockout: 5 * 60,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct TlsConfig {
+        /// PEM certificate chain and private key files
+        pub cert: Option<PathBuf>,
+        pub key: Option<PathBuf>,
+        /// Generate a self-signed certificate if the files don't exist yet
+        pub self_signed: bool,
+        /// Host names and IP addresses the self-signed certificate is valid for
+        pub hostnames: Vec<String>,
+    }
+
+    impl Default for TlsConfig {
+        fn default() -> Self {
+            TlsConfig {
+                cert: None,
+                key: None,
+                self_signed: false,
+                hostnames: vec!["localhost".to_string()],
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct OscConfig {
+        /// UDP address to receive OSC commands on. Without one, cue changes are only sent.
+        pub listen: Option<SocketAddr>,
+        /// Addresses every cue change is sent to
+        pub targets: Vec<SocketAddr>,
+        /// Room whose cues are sent and controlled
+        pub room: String,
+        /// OSC address of the messages sent on cue changes
+        pub send_address: String,
+        /// String arguments of the messages sent, where `{section}`, `{next}`, `{label}`, `{song}` and `{room}`
+        /// are replaced by the new cue
+        pub send_args: Vec<String>,
+        /// What each received OSC address does. Messages whose first argument is a zero number are ignored, as
+        /// buttons send 1 when pressed and 0 when released.
+        pub receive: HashMap<String, ControlAction>,
+        /// Addresses commands are accepted from. OSC has no login, so only this computer by default.
+        pub allow: Vec<IpAddr>,
+    }
+
+    impl Default for OscConfig {
+        fn default() -> Self {
+            OscConfig {
+                listen: None,
+                targets: Vec::new(),
+                room: DEFAULT_ROOM.to_string(),
+                send_address: "/cue".to_string(),
+                send_args: vec!["{section}".to_string(), "{next}".to_string()],
+                receive: [
+                    ("/cue/set", ControlAction::Set),
+                    ("/cue/stage", ControlAction::Stage),
+                    ("/cue/clear", ControlAction::Clear),
+                    ("/cue/next", ControlAction::Next),
+                    ("/cue/previous", ControlAction::Previous),
+                    ("/cue/go", ControlAction::Go),
+                    ("/cue/undo", ControlAction::Undo),
+                ]
+                .into_iter()
+                .map(|(address, command)| (address.to_string(), command))
+                .collect(),
+                allow: vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)],
+            }
+        }
+    }
+
+    #[cfg(feature = "midi")]
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct MidiConfig {
+        /// Name the server's ports are listed under
+        pub client_name: String,
+        /// Connect to the first port whose name contains these. Without them, virtual ports are opened for other
+        /// programs and devices to connect to.
+        pub input: Option<String>,
+        pub output: Option<String>,
+        /// Room whose cues are sent and controlled
+        pub room: String,
+        /// Messages received that change the cues
+        pub receive: Vec<MidiTrigger>,
+        /// Messages sent when the current cue changes
+        pub send: Vec<MidiNotification>,
+    }
+
+    #[cfg(feature = "midi")]
+    impl Default for MidiConfig {
+        fn default() -> Self {
+            MidiConfig {
+                client_name: "Song Sequence Director".to_string(),
+                input: None,
+                output: None,
+                room: DEFAULT_ROOM.to_string(),
+                receive: Vec::new(),
+                send: Vec::new(),
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct ObsConfig {
+        /// Address of obs-websocket, as set under Tools → WebSocket Server Settings in OBS
+        pub url: String,
+        /// Needed if authentication is turned on in OBS
+        pub password: Option<String>,
+        /// Room whose cues drive OBS
+        pub room: String,
+        /// What OBS does for each cue
+        pub rules: Vec<ObsRule>,
+    }
+
+    impl Default for ObsConfig {
+        fn default() -> Self {
+            ObsConfig {
+                url: "ws://127.0.0.1:4455".to_string(),
+                password: None,
+                room: DEFAULT_ROOM.to_string(),
+                rules: Vec::new(),
+            }
+        }
+    }
+
+    /// HTTP requests sent to other automations on every cue change
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct WebhooksConfig {
+        pub targets: Vec<WebhookTarget>,
+        /// Seconds to wait for a target to answer
+        pub timeout: u64,
+        /// Further attempts after a failed delivery, each waiting twice as long as the one before
+        pub retries: u32,
+        /// Deliveries that failed every attempt are appended to this file, one JSON object per line
+        pub dead_letters: PathBuf,
+    }
+
+    impl Default for WebhooksConfig {
+        fn default() -> Self {
+            WebhooksConfig {
+                targets: Vec::new(),
+                timeout: 5,
+                retries: 4,
+                dead_letters: PathBuf::from("webhook-dead-letters.jsonl"),
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct WebhookTarget {
+        pub url: String,
+        /// Key the requests are signed with, shared with the target
+        pub secret: String,
+        /// Rooms whose changes are sent. Changes in every room are sent if left out.
+        #[serde(default)]
+        pub rooms: Vec<String>,
+    }
+
+    /// Where the director state is saved so that it survives server restarts
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(tag = "backend", rename_all = "lowercase", deny_unknown_fields)]
+    pub enum PersistenceConfig {
+        None,
+        Json { path: PathBuf },
+        #[cfg(feature = "sqlite")]
+        Sqlite { path: PathBuf },
+    }
+
+    impl Default for PersistenceConfig {
+        fn default() -> Self {
+            PersistenceConfig::Json {
+                path: PathBuf::from("director-state.json"),
+            }
+        }
+    }
+
+    /// Where the songs imported into the library are kept
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(tag = "backend", rename_all = "lowercase", deny_unknown_fields)]
+    pub enum LibraryConfig {
+        None,
+        Json { path: PathBuf },
+        #[cfg(feature = "sqlite")]
+        Sqlite { path: PathBuf },
+    }
+
+    impl Default for LibraryConfig {
+        fn default() -> Self {
+            LibraryConfig::Json {
+                path: PathBuf::from("song-library.json"),
+            }
+        }
+    }
+}}
@@ -0,0 +1,570 @@
+use std::time::Duration;
+
+use cfg_if::cfg_if;
+use futures::channel::mpsc::UnboundedSender;
+use leptos::*;
+
+use crate::presence::Registration;
+use crate::protocol::{ClientMessage, ServerMessage};
+
+/// State of a page's connection to the server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connecting,
+    Connected,
+    Reconnecting,
+    /// Several attempts in a row have failed. Attempts continue at the slowest rate.
+    Offline,
+}
+
+impl ConnectionStatus {
+    pub fn label(self) -> &'static str {
+        match self {
+            ConnectionStatus::Connecting => "Connecting…",
+            ConnectionStatus::Connected => "Connected",
+            ConnectionStatus::Reconnecting => "Reconnecting…",
+            ConnectionStatus::Offline => "Offline",
+        }
+    }
+
+    pub fn class(self) -> &'static str {
+        match self {
+            ConnectionStatus::Connecting => "connecting",
+            ConnectionStatus::Connected => "connected",
+            ConnectionStatus::Reconnecting => "reconnecting",
+            ConnectionStatus::Offline => "offline",
+        }
+    }
+}
+
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Delay before the next attempt after `failures` failed attempts in a row, doubling each time up to the maximum.
+/// `random` in `0.0..1.0` adds up to 50% jitter, so that every display doesn't reconnect at the same moment after
+/// a server restart.
+pub fn retry_delay(failures: u32, random: f64) -> Duration {
+    let delay = INITIAL_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY);
+    delay.mul_f64(1.0 + random / 2.0)
+}
+
+cfg_if! { if #[cfg(any(test, not(feature = "ssr")))] {
+    /// Failed attempts in a row after which the connection is reported as offline
+    const OFFLINE_AFTER_FAILURES: u32 = 5;
+    /// Failed or short-lived connections in a row after which the next transport is tried
+    const FALLBACK_AFTER_FAILURES: u32 = 3;
+    /// A connection lasting this long counts as working, even though it was lost in the end
+    const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+    /// How a page receives updates. The WebSocket is preferred, and the others are fallbacks for proxies and
+    /// networks that break it.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Transport {
+        WebSocket,
+        /// Server-sent events, with messages to the server sent as separate requests
+        EventSource,
+        LongPoll,
+    }
+
+    impl Transport {
+        /// The transport to try once this one keeps failing, starting over with the WebSocket after the last one
+        fn fallback(self) -> Self {
+            match self {
+                Transport::WebSocket => Transport::EventSource,
+                Transport::EventSource => Transport::LongPoll,
+                Transport::LongPoll => Transport::WebSocket,
+            }
+        }
+    }
+
+    /// The connection attempts of a page so far, which decide the transport and the delay before the next attempt
+    struct Attempts {
+        transport: Transport,
+        /// Attempts in a row that failed, counting a lost connection as the first
+        failures: u32,
+        /// Attempts in a row with the current transport that failed or didn't last
+        transport_failures: u32,
+    }
+
+    impl Attempts {
+        fn new() -> Self {
+            Attempts {
+                transport: Transport::WebSocket,
+                failures: 0,
+                transport_failures: 0,
+            }
+        }
+
+        /// Records an attempt that failed, or that connected and was lost after `lasted`. Returns the transport
+        /// switched to if the current one keeps failing.
+        fn ended(&mut self, lasted: Option<Duration>) -> Option<Transport> {
+            match lasted {
+                None => {
+                    self.failures += 1;
+                    self.transport_failures += 1;
+                }
+                Some(lasted) => {
+                    self.failures = 1;
+                    self.transport_failures = if lasted >= STABLE_CONNECTION { 0 } else { self.transport_failures + 1 };
+                }
+            }
+            if self.transport_failures < FALLBACK_AFTER_FAILURES {
+                return None;
+            }
+            self.transport = self.transport.fallback();
+            self.transport_failures = 0;
+            Some(self.transport)
+        }
+
+        /// Status to show until the next attempt
+        fn status(&self) -> ConnectionStatus {
+            if self.failures >= OFFLINE_AFTER_FAILURES {
+                ConnectionStatus::Offline
+            } else {
+                ConnectionStatus::Reconnecting
+            }
+        }
+    }
+}}
+
+/// A page's cue socket, kept open by [`connect_cue_socket`]
+#[derive(Clone, Copy)]
+pub struct CueSocket {
+    pub status: ReadSignal<ConnectionStatus>,
+    outgoing: StoredValue<Option<UnboundedSender<ClientMessage>>>,
+}
+
+impl CueSocket {
+    /// Sends `message` to the server, or once reconnected if the socket is currently disconnected
+    pub fn send(&self, message: ClientMessage) {
+        self.outgoing.with_value(|outgoing| {
+            if let Some(outgoing) = outgoing {
+                let _ = outgoing.unbounded_send(message);
+            }
+        });
+    }
+}
+
+/// Keeps a WebSocket to the cues of `room` open for as long as `cx` lives, registered as `device`, passing every
+/// message to `on_message`.
+/// After the connection is lost, reconnects with exponential backoff. The server starts every connection with a
+/// snapshot, so nothing missed in the meantime needs fetching separately. If the WebSocket can't be opened or keeps
+/// dropping, falls back to server-sent events and then to long polling. Does nothing when rendering on the server.
+pub fn connect_cue_socket(
+    cx: Scope,
+    room: String,
+    device: Registration,
+    on_message: impl Fn(ServerMessage) + 'static,
+) -> CueSocket {
+    let (status, set_status) = create_signal(cx, ConnectionStatus::Connecting);
+    let outgoing = store_value(cx, None);
+
+    cfg_if! {
+        if #[cfg(not(feature = "ssr"))] {
+            use futures::channel::{mpsc, oneshot};
+            use futures::future::{select, Either};
+
+            let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded();
+            outgoing.set_value(Some(outgoing_tx));
+            // Stops the loop below once the page is left
+            let (cancel_tx, mut cancelled) = oneshot::channel::<()>();
+            on_cleanup(cx, move || {
+                let _ = cancel_tx.send(());
+            });
+
+            spawn_local(async move {
+                let link = Link {
+                    room,
+                    device,
+                    key: format!("{:013x}", (js_sys::Math::random() * (1u64 << 52) as f64) as u64),
+                    set_status,
+                };
+                let mut attempts = Attempts::new();
+                loop {
+                    let ended = match attempts.transport {
+                        Transport::WebSocket => run_socket(&link, &mut outgoing_rx, &mut cancelled, &on_message).await,
+                        Transport::EventSource => run_event_source(&link, &mut outgoing_rx, &mut cancelled, &on_message).await,
+                        Transport::LongPoll => run_long_poll(&link, &mut outgoing_rx, &mut cancelled, &on_message).await,
+                    };
+                    let lasted = match ended {
+                        Ended::Cancelled => return,
+                        Ended::Failed(err) => {
+                            warn!("{}", err);
+                            None
+                        }
+                        Ended::Lost(lasted) => Some(lasted),
+                    };
+                    if let Some(transport) = attempts.ended(lasted) {
+                        log!("Switching cue updates to {:?}", transport);
+                    }
+
+                    set_status.set(attempts.status());
+                    let delay = retry_delay(attempts.failures, js_sys::Math::random());
+                    if let Either::Right(_) = select(Box::pin(sleep(delay)), &mut cancelled).await {
+                        return;
+                    }
+                }
+            });
+        } else {
+            let _ = (room, device, on_message, set_status);
+        }
+    }
+
+    CueSocket { status, outgoing }
+}
+
+cfg_if! { if #[cfg(not(feature = "ssr"))] {
+    use futures::channel::mpsc::UnboundedReceiver;
+    use futures::channel::oneshot;
+    use futures::future::{select, Either, LocalBoxFuture};
+    use futures::{SinkExt, StreamExt};
+    use gloo_net::eventsource::futures::EventSource;
+    use gloo_net::http::Request;
+    use gloo_net::websocket::{futures::WebSocket, Message, State};
+
+    use crate::fallback::POLL_TIMEOUT;
+    use crate::protocol::{decode, decode_json_batch, Encoding, Frame, SUBPROTOCOLS};
+
+    /// A fallback connection that receives nothing for this long is given up. The server sends something at least
+    /// every heartbeat or poll timeout.
+    const SILENCE_TIMEOUT: Duration = Duration::from_secs(POLL_TIMEOUT.as_secs() + 15);
+
+    /// How a connection ended
+    enum Ended {
+        /// It could not be established
+        Failed(String),
+        /// It was lost after being connected for the given time
+        Lost(Duration),
+        /// The page was left
+        Cancelled,
+    }
+
+    /// What every transport needs to register the page with the server
+    struct Link {
+        room: String,
+        device: Registration,
+        /// Identifies the page across the separate requests of the fallbacks
+        key: String,
+        set_status: WriteSignal<ConnectionStatus>,
+    }
+
+    impl Link {
+        fn fallback_url(&self, path: &str) -> String {
+            format!("/ws/{}?{}&key={}", path, cue_query(&self.room, &self.device), self.key)
+        }
+    }
+
+    fn now() -> f64 {
+        js_sys::Date::now()
+    }
+
+    fn lasted(since: f64) -> Duration {
+        Duration::from_millis((now() - since).max(0.0) as u64)
+    }
+
+    async fn run_socket(
+        link: &Link,
+        outgoing_rx: &mut UnboundedReceiver<ClientMessage>,
+        cancelled: &mut oneshot::Receiver<()>,
+        on_message: &dyn Fn(ServerMessage),
+    ) -> Ended {
+        let (mut socket, encoding) = match open_socket(&link.room, &link.device).await {
+            Ok(opened) => opened,
+            Err(err) => return Ended::Failed(err),
+        };
+        link.set_status.set(ConnectionStatus::Connected);
+        let connected_at = now();
+        loop {
+            let message = match select(select(socket.next(), outgoing_rx.next()), &mut *cancelled).await {
+                Either::Left((Either::Left((Some(Ok(message)), _)), _)) => message,
+                Either::Left((Either::Left((Some(Err(err)), _)), _)) => {
+                    warn!("Error receiving message from WebSocket: {}", err);
+                    break;
+                }
+                Either::Left((Either::Left((None, _)), _)) => break,
+                Either::Left((Either::Right((Some(message), _)), _)) => {
+                    if let Err(err) = send_message(&mut socket, encoding, &message).await {
+                        warn!("{}", err);
+                        break;
+                    }
+                    continue;
+                }
+                Either::Left((Either::Right((None, _)), _)) | Either::Right(_) => {
+                    let _ = socket.close(None, None);
+                    return Ended::Cancelled;
+                }
+            };
+            let frame = match message {
+                Message::Text(text) => Frame::Text(text),
+                Message::Bytes(bytes) => Frame::Binary(bytes),
+            };
+            match decode::<ServerMessage>(&frame) {
+                Ok(ServerMessage::Ping { id }) => {
+                    if let Err(err) = send_message(&mut socket, encoding, &ClientMessage::Pong { id }).await {
+                        warn!("{}", err);
+                        break;
+                    }
+                }
+                Ok(message) => on_message(message),
+                Err(err) => warn!("{}", err),
+            }
+        }
+        Ended::Lost(lasted(connected_at))
+    }
+
+    /// Opens the socket and waits until it is connected
+    async fn open_socket(room: &str, device: &Registration) -> Result<(WebSocket, Encoding), String> {
+        let url = cue_socket_url(room, device)?;
+        let mut socket = WebSocket::open_with_protocols(&url, &SUBPROTOCOLS)
+            .map_err(|err| format!("could not open WebSocket: {}", err))?;
+        // Ready as soon as the socket is no longer connecting, whether that's because it opened or failed
+        let _ = futures::future::poll_fn(|cx| socket.poll_ready_unpin(cx)).await;
+        if !matches!(socket.state(), State::Open) {
+            return Err(format!("could not connect to {}", url));
+        }
+        let encoding = Encoding::from_subprotocol(&socket.protocol())
+            .ok_or_else(|| format!("server doesn't support any of the protocols {:?}", SUBPROTOCOLS))?;
+        Ok((socket, encoding))
+    }
+
+    async fn send_message(socket: &mut WebSocket, encoding: Encoding, message: &ClientMessage) -> Result<(), String> {
+        let message = match encoding.encode(message).map_err(|err| err.to_string())? {
+            Frame::Text(text) => Message::Text(text),
+            Frame::Binary(bytes) => Message::Bytes(bytes),
+        };
+        socket.send(message).await.map_err(|err| format!("could not send message: {}", err))
+    }
+
+    /// Receives the messages as server-sent events. The browser retries a broken event stream by itself, so the
+    /// connection only counts as open once the first event arrives, and is given up after a silence.
+    async fn run_event_source(
+        link: &Link,
+        outgoing_rx: &mut UnboundedReceiver<ClientMessage>,
+        cancelled: &mut oneshot::Receiver<()>,
+        on_message: &dyn Fn(ServerMessage),
+    ) -> Ended {
+        let url = link.fallback_url("events");
+        let mut source = match EventSource::new(&url) {
+            Ok(source) => source,
+            Err(err) => return Ended::Failed(format!("could not open event stream: {}", err)),
+        };
+        let mut events = match source.subscribe("message") {
+            Ok(events) => events,
+            Err(err) => return Ended::Failed(format!("could not open event stream: {}", err)),
+        };
+        let mut connected_at = None;
+        loop {
+            let silence = Box::pin(sleep(SILENCE_TIMEOUT));
+            match select(select(events.next(), outgoing_rx.next()), select(&mut *cancelled, silence)).await {
+                Either::Left((Either::Left((Some(Ok((_, event))), _)), _)) => {
+                    if connected_at.is_none() {
+                        link.set_status.set(ConnectionStatus::Connected);
+                        connected_at = Some(now());
+                    }
+                    let frame = Frame::Text(event.data().as_string().unwrap_or_default());
+                    match decode::<ServerMessage>(&frame) {
+                        // The server only sends these to keep the stream and its lease alive
+                        Ok(ServerMessage::Ping { .. }) => {}
+                        Ok(message) => on_message(message),
+                        Err(err) => warn!("{}", err),
+                    }
+                }
+                Either::Left((Either::Left((Some(Err(err)), _)), _)) => {
+                    warn!("Error receiving server-sent event: {}", err);
+                    break;
+                }
+                Either::Left((Either::Left((None, _)), _)) => break,
+                Either::Left((Either::Right((Some(message), _)), _)) => post_message(link, &message, on_message).await,
+                Either::Left((Either::Right((None, _)), _)) | Either::Right((Either::Left(_), _)) => {
+                    source.close();
+                    return Ended::Cancelled;
+                }
+                Either::Right((Either::Right(_), _)) => {
+                    warn!("No server-sent events for {:?}", SILENCE_TIMEOUT);
+                    break;
+                }
+            }
+        }
+        source.close();
+        match connected_at {
+            Some(connected_at) => Ended::Lost(lasted(connected_at)),
+            None => Ended::Failed(format!("could not connect to {}", url)),
+        }
+    }
+
+    /// Repeatedly asks for anything newer than the last snapshot, each request waiting on the server until there is
+    async fn run_long_poll(
+        link: &Link,
+        outgoing_rx: &mut UnboundedReceiver<ClientMessage>,
+        cancelled: &mut oneshot::Receiver<()>,
+        on_message: &dyn Fn(ServerMessage),
+    ) -> Ended {
+        let mut since = None;
+        let mut connected_at = None;
+        // Kept across messages sent in the meantime, so that sending doesn't restart the poll
+        let mut poll: Option<LocalBoxFuture<'static, Result<Vec<ServerMessage>, String>>> = None;
+        let error = loop {
+            let url = match since {
+                Some(since) => format!("{}&since={}", link.fallback_url("poll"), since),
+                None => link.fallback_url("poll"),
+            };
+            let request = poll.get_or_insert_with(|| Box::pin(fetch_messages(Request::get(&url).build())));
+            let silence = Box::pin(sleep(SILENCE_TIMEOUT));
+            match select(select(request, outgoing_rx.next()), select(&mut *cancelled, silence)).await {
+                Either::Left((Either::Left((result, _)), _)) => {
+                    poll = None;
+                    let messages = match result {
+                        Ok(messages) => messages,
+                        Err(err) => break err,
+                    };
+                    if connected_at.is_none() {
+                        link.set_status.set(ConnectionStatus::Connected);
+                        connected_at = Some(now());
+                    }
+                    for message in messages {
+                        if let ServerMessage::Snapshot { sequence, .. } = message {
+                            since = Some(sequence);
+                        }
+                        on_message(message);
+                    }
+                }
+                Either::Left((Either::Right((Some(message), _)), _)) => post_message(link, &message, on_message).await,
+                Either::Left((Either::Right((None, _)), _)) | Either::Right((Either::Left(_), _)) => return Ended::Cancelled,
+                Either::Right((Either::Right(_), _)) => break format!("no answer to long poll for {:?}", SILENCE_TIMEOUT),
+            }
+        };
+        match connected_at {
+            Some(connected_at) => {
+                warn!("{}", error);
+                Ended::Lost(lasted(connected_at))
+            }
+            None => Ended::Failed(error),
+        }
+    }
+
+    /// Sends `message` as the fallbacks do, passing the server's answers to `on_message`
+    async fn post_message(link: &Link, message: &ClientMessage, on_message: &dyn Fn(ServerMessage)) {
+        let body = match Encoding::Json.encode(message) {
+            Ok(Frame::Text(text)) => text,
+            Ok(Frame::Binary(_)) => unreachable!("JSON is sent as text"),
+            Err(err) => return warn!("{}", err),
+        };
+        let request = Request::post(&link.fallback_url("send")).header("Content-Type", "application/json").body(body);
+        match fetch_messages(request).await {
+            Ok(answers) => answers.into_iter().for_each(on_message),
+            Err(err) => warn!("{}", err),
+        }
+    }
+
+    /// Sends `request` and decodes the JSON array of messages it is answered with
+    async fn fetch_messages(request: Result<Request, gloo_net::Error>) -> Result<Vec<ServerMessage>, String> {
+        let request = request.map_err(|err| format!("could not build request: {}", err))?;
+        let url = request.url();
+        let response = request.send().await.map_err(|err| format!("could not reach {}: {}", url, err))?;
+        if !response.ok() {
+            return Err(format!("{} answered {}", url, response.status()));
+        }
+        let text = response.text().await.map_err(|err| format!("could not read answer from {}: {}", url, err))?;
+        decode_json_batch(&text).map_err(|err| err.to_string())
+    }
+
+    async fn sleep(duration: Duration) {
+        let (tx, rx) = oneshot::channel();
+        set_timeout(
+            move || {
+                let _ = tx.send(());
+            },
+            duration,
+        );
+        let _ = rx.await;
+    }
+
+    /// URL of the cue WebSocket for `room` on the server that served the page, secure if the page is
+    fn cue_socket_url(room: &str, device: &Registration) -> Result<String, String> {
+        use leptos_dom::helpers::location;
+
+        let host = location().host().map_err(|_| "could not read location host".to_string())?;
+        let scheme = match location().protocol().as_deref() {
+            Ok("https:") => "wss",
+            _ => "ws",
+        };
+        Ok(format!("{}://{}/ws?{}", scheme, host, cue_query(room, device)))
+    }
+
+    /// Query registering `device` in `room`, shared by the cue socket and its fallbacks
+    fn cue_query(room: &str, device: &Registration) -> String {
+        let mut query = format!("room={}", room);
+        for (key, value) in [("name", &device.name), ("role", &device.role), ("groups", &device.groups)] {
+            if let Some(value) = value {
+                query.push_str(&format!("&{}={}", key, js_sys::encode_uri_component(value)));
+            }
+        }
+        query
+    }
+}}
+
+/// Small badge showing the state of the connection to the server
+#[component]
+pub fn ConnectionIndicator(cx: Scope, status: ReadSignal<ConnectionStatus>) -> impl IntoView {
+    view! { cx,
+        <div class=move || format!("connection-status {}", status.get().class())>{move || status.get().label()}</div>
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_with_jitter_up_to_the_maximum() {
+        assert_eq!(retry_delay(1, 0.0), Duration::from_millis(500));
+        assert_eq!(retry_delay(1, 1.0), Duration::from_millis(750));
+        assert_eq!(retry_delay(2, 0.0), Duration::from_secs(1));
+        assert_eq!(retry_delay(4, 0.0), Duration::from_secs(4));
+        assert_eq!(retry_delay(4, 1.0), Duration::from_secs(6));
+        // 500 ms doubled 6 times is past the cap
+        assert_eq!(retry_delay(7, 0.0), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(7, 1.0), Duration::from_secs(45));
+        assert_eq!(retry_delay(u32::MAX, 0.5), Duration::from_millis(37_500));
+        // No failure yet counts like the first
+        assert_eq!(retry_delay(0, 0.0), INITIAL_RETRY_DELAY);
+    }
+
+    #[test]
+    fn falls_back_from_websocket_to_event_source_to_long_poll_and_back() {
+        let mut attempts = Attempts::new();
+        let mut transports = Vec::new();
+        for _ in 0..3 * FALLBACK_AFTER_FAILURES {
+            transports.extend(attempts.ended(None));
+        }
+        assert_eq!(transports, [Transport::EventSource, Transport::LongPoll, Transport::WebSocket]);
+    }
+
+    #[test]
+    fn stable_connections_keep_the_transport() {
+        let mut attempts = Attempts::new();
+        assert_eq!(attempts.ended(None), None);
+        assert_eq!(attempts.ended(None), None);
+        // Lost after working for a while, so the earlier failures don't count towards falling back
+        assert_eq!(attempts.ended(Some(STABLE_CONNECTION)), None);
+        assert_eq!(attempts.failures, 1);
+        assert_eq!(attempts.ended(None), None);
+        assert_eq!(attempts.ended(None), None);
+        assert_eq!(attempts.ended(Some(Duration::from_secs(1))), Some(Transport::EventSource));
+    }
+
+    #[test]
+    fn reports_offline_after_repeated_failures() {
+        let mut attempts = Attempts::new();
+        for _ in 1..OFFLINE_AFTER_FAILURES {
+            attempts.ended(None);
+            assert_eq!(attempts.status(), ConnectionStatus::Reconnecting);
+        }
+        attempts.ended(None);
+        assert_eq!(attempts.status(), ConnectionStatus::Offline);
+        attempts.ended(Some(Duration::from_secs(1)));
+        assert_eq!(attempts.status(), ConnectionStatus::Reconnecting);
+    }
+}
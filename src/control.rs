@@ -0,0 +1,113 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use serde::Deserialize;
+    use thiserror::Error;
+    use utoipa::ToSchema;
+
+    use crate::app::{section_segments_to_string, section_string_to_segments, DirectorState, SectionTuple};
+    use crate::rooms::Room;
+    use crate::vocabulary::{Vocabulary, VocabularyError};
+
+    /// What an input from a control surface, e.g. an OSC address or a MIDI note, does to a room's cues
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ToSchema)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ControlAction {
+        /// Sets the current cue to the one given with the input, e.g. `V2`
+        Set,
+        /// Stages the given cue as the next one
+        Stage,
+        Clear,
+        /// Moves to the next or previous section of the setlist
+        Next,
+        Previous,
+        /// Makes the staged cue current
+        Go,
+        Undo,
+    }
+
+    #[derive(Debug, Error)]
+    pub enum ControlError {
+        #[error("needs a cue such as \"V2\"")]
+        MissingCue,
+        #[error("\"{0}\" is not a cue")]
+        InvalidCue(String),
+        #[error(transparent)]
+        Vocabulary(#[from] VocabularyError),
+    }
+
+    /// Applies `action` to `room`, the same way as the buttons on the director page. `cue` is required by
+    /// [`ControlAction::Set`] and [`ControlAction::Stage`], and `source` names the sender in the audit log.
+    pub fn apply_action(
+        room: &Room,
+        vocabulary: &Vocabulary,
+        action: ControlAction,
+        cue: Option<&str>,
+        source: &str,
+    ) -> Result<(), ControlError> {
+        let section = match action {
+            ControlAction::Set | ControlAction::Stage => {
+                let cue = cue.ok_or(ControlError::MissingCue)?.trim();
+                Some(section_string_to_segments(cue).ok_or_else(|| ControlError::InvalidCue(cue.to_string()))?)
+            }
+            _ => None,
+        };
+        apply_section_action(room, vocabulary, action, section, source)
+    }
+
+    /// Like [`apply_action`], with the cue already parsed, for the director page's server functions
+    pub fn apply_section_action(
+        room: &Room,
+        vocabulary: &Vocabulary,
+        action: ControlAction,
+        section: Option<SectionTuple>,
+        source: &str,
+    ) -> Result<(), ControlError> {
+        let audit = |description: std::fmt::Arguments| {
+            log::info!(target: "audit", "{} in room {}: {}", source, room.id(), description);
+        };
+        match action {
+            ControlAction::Set | ControlAction::Stage => {
+                let section = section.ok_or(ControlError::MissingCue)?;
+                vocabulary.validate(&section)?;
+                if action == ControlAction::Set {
+                    audit(format_args!("set section to {}", section_segments_to_string(&section)));
+                    room.modify(|s| {
+                        s.section = section;
+                        true
+                    });
+                } else {
+                    audit(format_args!("staged next section {}", section_segments_to_string(&section)));
+                    room.modify(|s| {
+                        s.next = section;
+                        true
+                    });
+                }
+            }
+            ControlAction::Clear => {
+                audit(format_args!("cleared section"));
+                room.modify(|s| {
+                    s.section = (None, None);
+                    true
+                });
+            }
+            ControlAction::Next => {
+                audit(format_args!("moved to next section"));
+                room.modify(|s| s.step(true));
+            }
+            ControlAction::Previous => {
+                audit(format_args!("moved to previous section"));
+                room.modify(|s| s.step(false));
+            }
+            ControlAction::Go => {
+                audit(format_args!("promoted next section"));
+                room.modify(DirectorState::promote_next);
+            }
+            ControlAction::Undo => {
+                audit(format_args!("undid last change"));
+                room.undo();
+            }
+        }
+        Ok(())
+    }
+}}
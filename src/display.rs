@@ -0,0 +1,250 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use leptos_router::ParamsMap;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{section_segments_to_string, SectionTuple};
+
+/// Named display styles from the configuration, chosen with `/view?profile=<name>`
+pub type DisplayProfiles = HashMap<String, DisplayStyle>;
+
+/// How the display page shows the cues. Values are CSS, e.g. `"Source Sans Pro"`, `6rem` or `#ffcc00`, and the
+/// stylesheet's defaults apply to everything left out. The rest draws attention to a new cue.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayStyle {
+    pub font: Option<String>,
+    /// Font size of the current cue. The staged cue is shown smaller in proportion.
+    pub size: Option<String>,
+    pub color: Option<String>,
+    /// `transparent` shows whatever is behind the page, e.g. the video under an OBS browser source
+    pub background: Option<String>,
+    pub align: Option<DisplayAlign>,
+    pub padding: Option<String>,
+    /// Colour of an outline around the text, which keeps it readable on top of video
+    pub outline: Option<String>,
+    pub outline_width: Option<String>,
+    pub effect: Option<ChangeEffect>,
+    /// How long the effect lasts, in seconds
+    pub effect_seconds: Option<u32>,
+    /// Keep the effect going until the display is tapped
+    pub until_ack: Option<bool>,
+    /// Play a short tone on every change that has no sound of its own
+    pub chime: Option<bool>,
+    /// Sound clips by cue, e.g. `V` for every verse or `C2`, as URLs such as `/sounds/chorus.mp3`
+    pub sounds: BTreeMap<String, String>,
+}
+
+/// Animation of the display page when the cue changes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeEffect {
+    Flash,
+    Pulse,
+    Invert,
+}
+
+impl ChangeEffect {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "flash" => Some(ChangeEffect::Flash),
+            "pulse" => Some(ChangeEffect::Pulse),
+            "invert" => Some(ChangeEffect::Invert),
+            _ => None,
+        }
+    }
+
+    /// Class of the display page while the effect runs
+    pub fn class(self) -> &'static str {
+        match self {
+            ChangeEffect::Flash => "effect-flash",
+            ChangeEffect::Pulse => "effect-pulse",
+            ChangeEffect::Invert => "effect-invert",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayAlign {
+    Left,
+    Center,
+    Right,
+}
+
+impl DisplayAlign {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "left" => Some(DisplayAlign::Left),
+            "center" => Some(DisplayAlign::Center),
+            "right" => Some(DisplayAlign::Right),
+            _ => None,
+        }
+    }
+}
+
+/// Outline width used when only the colour is given
+const DEFAULT_OUTLINE_WIDTH: &str = "0.1em";
+const DEFAULT_EFFECT_SECONDS: u32 = 3;
+
+impl DisplayStyle {
+    /// The style given in the query of the display page, e.g. `?size=6rem&color=white&background=transparent` or
+    /// `?effect=pulse&until_ack=true`
+    pub fn from_query(query: &ParamsMap) -> Self {
+        let value = |name: &str| query.get(name).filter(|value| !value.is_empty()).cloned();
+        DisplayStyle {
+            font: value("font"),
+            size: value("size"),
+            color: value("color"),
+            background: value("background"),
+            align: query.get("align").and_then(|align| DisplayAlign::from_name(align)),
+            padding: value("padding"),
+            outline: value("outline"),
+            outline_width: value("outline_width"),
+            effect: query.get("effect").and_then(|effect| ChangeEffect::from_name(effect)),
+            effect_seconds: query.get("effect_seconds").and_then(|seconds| seconds.parse().ok()),
+            until_ack: query.get("until_ack").and_then(|until_ack| until_ack.parse().ok()),
+            chime: query.get("chime").and_then(|chime| chime.parse().ok()),
+            sounds: BTreeMap::new(),
+        }
+    }
+
+    /// This style with everything that `overrides` sets replaced
+    pub fn overridden_by(self, overrides: DisplayStyle) -> Self {
+        DisplayStyle {
+            font: overrides.font.or(self.font),
+            size: overrides.size.or(self.size),
+            color: overrides.color.or(self.color),
+            background: overrides.background.or(self.background),
+            align: overrides.align.or(self.align),
+            padding: overrides.padding.or(self.padding),
+            outline: overrides.outline.or(self.outline),
+            outline_width: overrides.outline_width.or(self.outline_width),
+            effect: overrides.effect.or(self.effect),
+            effect_seconds: overrides.effect_seconds.or(self.effect_seconds),
+            until_ack: overrides.until_ack.or(self.until_ack),
+            chime: overrides.chime.or(self.chime),
+            sounds: if overrides.sounds.is_empty() { self.sounds } else { overrides.sounds },
+        }
+    }
+
+    /// How long the change effect lasts, or `None` if it lasts until the display is tapped
+    pub fn effect_duration(&self) -> Option<Duration> {
+        match self.until_ack {
+            Some(true) => None,
+            _ => Some(Duration::from_secs(self.effect_seconds.unwrap_or(DEFAULT_EFFECT_SECONDS).into())),
+        }
+    }
+
+    /// The clip for `section`, the one for the whole cue before the one for its letter
+    pub fn sound(&self, section: &SectionTuple) -> Option<&str> {
+        let letter = section.0.map(String::from).unwrap_or_default();
+        [section_segments_to_string(section), letter]
+            .iter()
+            .filter(|cue| !cue.is_empty())
+            .find_map(|cue| self.sounds.get(cue))
+            .map(String::as_str)
+    }
+
+    /// The custom properties the stylesheet reads for the display page, for its `style` attribute. Values that
+    /// could end the declaration or load anything are left out.
+    pub fn css_variables(&self) -> String {
+        let (justify, text_align) = match self.align {
+            Some(DisplayAlign::Left) => (Some("flex-start"), Some("left")),
+            Some(DisplayAlign::Center) => (Some("center"), Some("center")),
+            Some(DisplayAlign::Right) => (Some("flex-end"), Some("right")),
+            None => (None, None),
+        };
+        let outline = self.outline.as_ref().map(|color| {
+            format!("{} {}", self.outline_width.as_deref().unwrap_or(DEFAULT_OUTLINE_WIDTH), color)
+        });
+        [
+            ("font", self.font.as_deref()),
+            ("size", self.size.as_deref()),
+            ("color", self.color.as_deref()),
+            ("background", self.background.as_deref()),
+            ("justify", justify),
+            ("text-align", text_align),
+            ("padding", self.padding.as_deref()),
+            ("outline", outline.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.filter(|value| is_plain_css_value(value)).map(|value| (name, value)))
+        .map(|(name, value)| format!("--display-{}: {};", name, value))
+        .collect()
+    }
+}
+
+fn is_plain_css_value(value: &str) -> bool {
+    !value.contains([';', '{', '}', '<', '>', '\\']) && !value.to_ascii_lowercase().contains("url(")
+}
+
+/// Plays the clip at `url`, or a short tone when there's none. Browsers only play sound once the page has been
+/// interacted with, see [`unlock_audio`].
+pub fn play_cue_sound(url: Option<&str>) {
+    cfg_if::cfg_if! {
+        if #[cfg(not(feature = "ssr"))] {
+            let result = match url {
+                Some(url) => web_sys::HtmlAudioElement::new_with_src(url).and_then(|audio| audio.play().map(drop)),
+                None => play_chime(),
+            };
+            if let Err(err) = result {
+                leptos::warn!("Could not play sound: {:?}", err);
+            }
+        } else {
+            let _ = url;
+        }
+    }
+}
+
+/// Lets the page play sound, which browsers allow only from the handler of a tap or click
+pub fn unlock_audio() {
+    cfg_if::cfg_if! {
+        if #[cfg(not(feature = "ssr"))] {
+            if let Err(err) = audio_context().and_then(|context| context.resume().map(drop)) {
+                leptos::warn!("Could not enable sound: {:?}", err);
+            }
+        }
+    }
+}
+
+cfg_if::cfg_if! { if #[cfg(not(feature = "ssr"))] {
+    use wasm_bindgen::JsValue;
+    use web_sys::AudioContext;
+
+    thread_local! {
+        /// Browsers limit the number of audio contexts, so the page keeps one
+        static AUDIO_CONTEXT: std::cell::RefCell<Option<AudioContext>> = Default::default();
+    }
+
+    fn audio_context() -> Result<AudioContext, JsValue> {
+        AUDIO_CONTEXT.with(|context| {
+            let mut context = context.borrow_mut();
+            match &*context {
+                Some(context) => Ok(context.clone()),
+                None => {
+                    let created = AudioContext::new()?;
+                    *context = Some(created.clone());
+                    Ok(created)
+                }
+            }
+        })
+    }
+
+    /// Two rising notes that fade out
+    fn play_chime() -> Result<(), JsValue> {
+        let context = audio_context()?;
+        let now = context.current_time();
+        let oscillator = context.create_oscillator()?;
+        let gain = context.create_gain()?;
+        oscillator.frequency().set_value_at_time(880.0, now)?;
+        oscillator.frequency().set_value_at_time(1318.5, now + 0.15)?;
+        gain.gain().set_value_at_time(0.3, now)?;
+        gain.gain().exponential_ramp_to_value_at_time(0.001, now + 0.8)?;
+        oscillator.connect_with_audio_node(&gain)?;
+        gain.connect_with_audio_node(&context.destination())?;
+        oscillator.start()?;
+        oscillator.stop_with_when(now + 0.8)
+    }
+}}
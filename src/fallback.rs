@@ -0,0 +1,254 @@
+use std::time::Duration;
+
+use cfg_if::cfg_if;
+
+/// Time a long poll waits for a change before answering anyway
+pub const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use axum::body::Bytes;
+    use axum::extract::{ConnectInfo, Query, State};
+    use axum::response::sse::{Event, Sse};
+    use axum::response::IntoResponse;
+    use futures::channel::mpsc;
+    use futures::SinkExt;
+    use http::{header, HeaderMap, StatusCode};
+    use serde::Deserialize;
+
+    use crate::app::{change_messages, handle_client_message, snapshot_messages, AppState, SocketClient, SocketQuery};
+    use crate::presence::{LeaseGuard, HEARTBEAT_INTERVAL};
+    use crate::protocol::{encode_json_batch, Encoding, Frame, ServerMessage};
+    use crate::rooms::{Room, DEFAULT_ROOM};
+
+    /// Longer keys are rejected, as they are kept in memory
+    const MAX_KEY_LENGTH: usize = 64;
+    /// Messages queued for a slow event stream before the room's updates wait for it
+    const EVENT_QUEUE_LENGTH: usize = 16;
+
+    #[derive(Debug, Deserialize)]
+    pub struct FallbackQuery {
+        /// Chosen by the client, e.g. at random, to tell its requests apart from those of other devices
+        key: String,
+        /// Sequence number of the state the client knows about. A long poll with it waits for anything newer.
+        since: Option<u64>,
+    }
+
+    /// The room and client of a request
+    fn open(
+        app_state: &AppState,
+        query: SocketQuery,
+        fallback: &FallbackQuery,
+        addr: SocketAddr,
+        headers: &HeaderMap,
+    ) -> Result<(Arc<Room>, SocketClient), (StatusCode, String)> {
+        if fallback.key.is_empty() || fallback.key.len() > MAX_KEY_LENGTH {
+            return Err((StatusCode::BAD_REQUEST, "Invalid key".to_string()));
+        }
+        let room = app_state
+            .rooms
+            .get_or_create(query.room.as_deref().unwrap_or(DEFAULT_ROOM))
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        Ok((room, SocketClient::new(addr, query.device, &app_state.auth, headers)))
+    }
+
+    /// Lists the client as a device of the room for as long as it keeps making requests
+    fn lease(room: &Room, client: &SocketClient, key: &str) -> Result<(), (StatusCode, String)> {
+        let leased = room
+            .presence()
+            .lease(key, client.addr.ip(), client.name.clone(), client.role.clone(), client.groups.clone());
+        leased.then_some(()).ok_or_else(too_many_devices)
+    }
+
+    fn too_many_devices() -> (StatusCode, String) {
+        (StatusCode::TOO_MANY_REQUESTS, "Too many devices from this address".to_string())
+    }
+
+    fn json_batch(messages: &[ServerMessage]) -> Result<impl IntoResponse, (StatusCode, String)> {
+        let body = encode_json_batch(messages).map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        Ok(([(header::CONTENT_TYPE, "application/json")], body))
+    }
+
+    /// Sends the same messages as the cue socket as server-sent events, with JSON in each event's data.
+    /// The client can't answer over the stream, so it sends its messages to [`send_handler`] instead.
+    pub async fn events_handler(
+        State(app_state): State<AppState>,
+        Query(query): Query<SocketQuery>,
+        Query(fallback): Query<FallbackQuery>,
+        headers: HeaderMap,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ) -> Result<Sse<mpsc::Receiver<Result<Event, Infallible>>>, (StatusCode, String)> {
+        let (room, client) = open(&app_state, query, &fallback, addr, &headers)?;
+        let lease = room
+            .presence()
+            .hold(&fallback.key, client.addr.ip(), client.name.clone(), client.role.clone(), client.groups.clone())
+            .ok_or_else(too_many_devices)?;
+        let (tx, rx) = mpsc::channel(EVENT_QUEUE_LENGTH);
+        tokio::spawn(stream_events(tx, room, client, lease));
+        Ok(Sse::new(rx))
+    }
+
+    /// Feeds the room's updates into `tx` until the client disconnects
+    async fn stream_events(mut tx: mpsc::Sender<Result<Event, Infallible>>, room: Arc<Room>, client: SocketClient, lease: LeaseGuard) {
+        let mut section_rx = room.subscribe();
+        let mut known = section_rx.borrow_and_update().clone();
+        let mut devices_rx = room.presence().subscribe();
+        let mut messages = snapshot_messages(&client, &known);
+        if client.director {
+            messages.push(ServerMessage::Devices {
+                devices: devices_rx.borrow_and_update().clone(),
+            });
+        }
+        let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+        let mut ping_id = 0;
+        loop {
+            for message in &messages {
+                let data = match Encoding::Json.encode(message) {
+                    Ok(Frame::Text(text)) => text,
+                    Ok(Frame::Binary(_)) => unreachable!("JSON is sent as text"),
+                    Err(err) => {
+                        log::error!("{}", err);
+                        continue;
+                    }
+                };
+                if tx.send(Ok(Event::default().data(data))).await.is_err() {
+                    log::debug!("Event stream to {} closed", client.addr);
+                    return;
+                }
+            }
+            messages = tokio::select! {
+                changed = section_rx.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    let state = section_rx.borrow_and_update().clone();
+                    let messages = change_messages(&client, &known, &state);
+                    known = state;
+                    messages
+                }
+                Ok(()) = devices_rx.changed(), if client.director => {
+                    vec![ServerMessage::Devices { devices: devices_rx.borrow_and_update().clone() }]
+                }
+                _ = heartbeat.tick() => {
+                    // Sending fails once the client is gone, so an open stream is a sign of life
+                    lease.seen();
+                    ping_id += 1;
+                    vec![ServerMessage::Ping { id: ping_id }]
+                }
+            };
+        }
+    }
+
+    /// Answers with the snapshot messages of the cue socket as a JSON array, once the state has changed from the one
+    /// numbered `since` or after [`POLL_TIMEOUT`]
+    pub async fn poll_handler(
+        State(app_state): State<AppState>,
+        Query(query): Query<SocketQuery>,
+        Query(fallback): Query<FallbackQuery>,
+        headers: HeaderMap,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ) -> Result<impl IntoResponse, (StatusCode, String)> {
+        let (room, client) = open(&app_state, query, &fallback, addr, &headers)?;
+        lease(&room, &client, &fallback.key)?;
+        json_batch(&poll_messages(&room, &client, fallback.since).await)
+    }
+
+    /// The snapshot messages once the state has changed from the one numbered `since`. A lower number than `since`
+    /// counts as a change too, as the server starts numbering from 0 again when it restarts without persistence.
+    async fn poll_messages(room: &Room, client: &SocketClient, since: Option<u64>) -> Vec<ServerMessage> {
+        let mut section_rx = room.subscribe();
+        if let Some(since) = since {
+            let wait = section_rx.wait_for(|state| state.sequence != since);
+            let _ = tokio::time::timeout(POLL_TIMEOUT, wait).await;
+        }
+        let state = section_rx.borrow().clone();
+        let mut messages = snapshot_messages(client, &state);
+        if client.director {
+            messages.push(ServerMessage::Devices {
+                devices: room.presence().subscribe().borrow().clone(),
+            });
+        }
+        messages
+    }
+
+    /// Handles a JSON message that a client using [`events_handler`] or [`poll_handler`] would otherwise send
+    /// over the cue socket, answering with a JSON array of the server's answers
+    pub async fn send_handler(
+        State(app_state): State<AppState>,
+        Query(query): Query<SocketQuery>,
+        Query(fallback): Query<FallbackQuery>,
+        headers: HeaderMap,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        body: Bytes,
+    ) -> Result<impl IntoResponse, (StatusCode, String)> {
+        let (room, client) = open(&app_state, query, &fallback, addr, &headers)?;
+        lease(&room, &client, &fallback.key)?;
+        let presence = room.presence();
+        let frame = Frame::Text(String::from_utf8_lossy(&body).into_owned());
+        let answer = handle_client_message(&frame, &room, &client, |sequence| presence.ack_leased(&fallback.key, sequence));
+        json_batch(&answer.into_iter().collect::<Vec<_>>())
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::app::SocketClient;
+    use crate::protocol::ServerMessage;
+    use crate::rooms::RoomRegistry;
+
+    fn viewer() -> SocketClient {
+        SocketClient {
+            addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 50000)),
+            name: "Tablet".to_string(),
+            role: String::new(),
+            groups: Vec::new(),
+            director: false,
+        }
+    }
+
+    fn notice(messages: &[ServerMessage]) -> Option<&str> {
+        messages.iter().find_map(|message| match message {
+            ServerMessage::Message { text } => Some(text.as_str()),
+            _ => None,
+        })
+    }
+
+    #[tokio::test]
+    async fn poll_returns_the_cleared_notice() {
+        let rooms = RoomRegistry::new(None);
+        let room = rooms.get_or_create("default").unwrap();
+        room.modify(|state| {
+            state.notice = "Key change to A".to_string();
+            true
+        });
+        let messages = poll_messages(&room, &viewer(), None).await;
+        assert_eq!(notice(&messages), Some("Key change to A"));
+
+        let since = room.state().sequence;
+        room.modify(|state| {
+            state.notice.clear();
+            true
+        });
+        let messages = tokio::time::timeout(Duration::from_secs(1), poll_messages(&room, &viewer(), Some(since)))
+            .await
+            .expect("the poll waited although the state had changed");
+        assert_eq!(notice(&messages), Some(""));
+    }
+
+    #[tokio::test]
+    async fn poll_answers_at_once_after_the_sequence_restarted() {
+        let rooms = RoomRegistry::new(None);
+        let room = rooms.get_or_create("default").unwrap();
+        let messages = tokio::time::timeout(Duration::from_secs(1), poll_messages(&room, &viewer(), Some(40)))
+            .await
+            .expect("the poll waited for a sequence the server no longer has");
+        assert!(matches!(messages[0], ServerMessage::Snapshot { sequence: 0, .. }));
+    }
+}
@@ -0,0 +1,362 @@
+use std::num::NonZeroUsize;
+
+use leptos::*;
+use serde::{Deserialize, Serialize};
+
+use crate::vocabulary::CueDefinition;
+
+/// Local storage item remembering whether this device uses the keyboard shortcuts
+#[cfg(not(feature = "ssr"))]
+const ENABLED_ITEM: &str = "director-keyboard-shortcuts";
+
+/// Keyboard shortcuts of the director page, named as in `KeyboardEvent.key`, e.g. `PageDown` or `v`. Page-turner
+/// pedals usually send `PageUp` and `PageDown` or the arrow keys. Letters match in either case, and `Space` stands
+/// for the space bar. Keys for the cue buttons are set on each cue.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    /// Keys for the number buttons, the first one for 1
+    pub numbers: Vec<String>,
+    pub clear: Vec<String>,
+    pub undo: Vec<String>,
+    pub stage: Vec<String>,
+    pub go: Vec<String>,
+    pub next: Vec<String>,
+    pub previous: Vec<String>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        let keys = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect();
+        KeyBindings {
+            numbers: keys(&["1", "2", "3", "4", "5", "6", "7", "8", "9"]),
+            clear: keys(&["Backspace", "Delete", "-"]),
+            undo: keys(&["z"]),
+            stage: keys(&["s"]),
+            go: keys(&["Enter", "Space"]),
+            next: keys(&["PageDown", "ArrowRight"]),
+            previous: keys(&["PageUp", "ArrowLeft"]),
+        }
+    }
+}
+
+/// What a key does, the same as one of the buttons on the director page
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAction {
+    Cue(char),
+    Number(NonZeroUsize),
+    Clear,
+    Undo,
+    Stage,
+    Go,
+    Next,
+    Previous,
+}
+
+impl KeyAction {
+    pub fn description(self, cues: &[CueDefinition]) -> String {
+        match self {
+            KeyAction::Cue(code) => match cues.iter().find(|cue| cue.code == code) {
+                Some(cue) => format!("{} ({})", cue.label, code),
+                None => code.to_string(),
+            },
+            KeyAction::Number(number) => number.to_string(),
+            KeyAction::Clear => "Clear".to_string(),
+            KeyAction::Undo => "Undo".to_string(),
+            KeyAction::Stage => "Stage next".to_string(),
+            KeyAction::Go => "Go".to_string(),
+            KeyAction::Next => "Next section".to_string(),
+            KeyAction::Previous => "Previous section".to_string(),
+        }
+    }
+}
+
+/// Where a binding comes from. When a key is bound more than once, only the bindings from the highest source are
+/// kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum KeySource {
+    /// One of the default keys of the other buttons
+    BuiltIn,
+    /// A cue's code, used when the cue has no `key`
+    CueCode,
+    Configured,
+}
+
+impl KeyBindings {
+    /// Every key from the cues and the configuration with what it does and where it comes from, in the order of the
+    /// buttons
+    fn candidates(&self, cues: &[CueDefinition]) -> Vec<(String, KeyAction, KeySource)> {
+        let mut candidates: Vec<(String, KeyAction, KeySource)> = cues
+            .iter()
+            .map(|cue| match &cue.key {
+                Some(key) => (normalize_key(key), KeyAction::Cue(cue.code), KeySource::Configured),
+                None => (normalize_key(&cue.code.to_string()), KeyAction::Cue(cue.code), KeySource::CueCode),
+            })
+            .collect();
+        let defaults = KeyBindings::default();
+        let source = |key: &str, defaults: &[String]| {
+            if defaults.iter().any(|default| normalize_key(default) == normalize_key(key)) {
+                KeySource::BuiltIn
+            } else {
+                KeySource::Configured
+            }
+        };
+        candidates.extend(self.numbers.iter().zip((1..).filter_map(NonZeroUsize::new)).map(|(key, number)| {
+            let default = defaults.numbers.get(number.get() - 1..number.get()).unwrap_or_default();
+            (normalize_key(key), KeyAction::Number(number), source(key, default))
+        }));
+        for (keys, defaults, action) in [
+            (&self.clear, &defaults.clear, KeyAction::Clear),
+            (&self.undo, &defaults.undo, KeyAction::Undo),
+            (&self.stage, &defaults.stage, KeyAction::Stage),
+            (&self.go, &defaults.go, KeyAction::Go),
+            (&self.next, &defaults.next, KeyAction::Next),
+            (&self.previous, &defaults.previous, KeyAction::Previous),
+        ] {
+            candidates.extend(keys.iter().map(|key| (normalize_key(key), action, source(key, defaults))));
+        }
+        candidates
+    }
+
+    /// Every bound key with what it does, in the order of the buttons. Keys are normalized as by [`normalize_key`].
+    /// A cue's code takes the place of a default key of another button, and a configured key takes the place of
+    /// both.
+    pub fn bindings(&self, cues: &[CueDefinition]) -> Vec<(String, KeyAction)> {
+        let candidates = self.candidates(cues);
+        candidates
+            .iter()
+            .filter(|(key, _, source)| !candidates.iter().any(|(other, _, over)| other == key && over > source))
+            .map(|(key, action, _)| (key.clone(), *action))
+            .collect()
+    }
+
+    /// The bindings left out of [`KeyBindings::bindings`], each with the action that the key does instead
+    pub fn overridden(&self, cues: &[CueDefinition]) -> Vec<(String, KeyAction, KeyAction)> {
+        let candidates = self.candidates(cues);
+        candidates
+            .iter()
+            .filter_map(|(key, action, source)| {
+                let (_, winner, _) = candidates
+                    .iter()
+                    .filter(|(other, _, over)| other == key && over > source)
+                    .max_by_key(|(_, _, over)| *over)?;
+                Some((display_key(key), *action, *winner))
+            })
+            .collect()
+    }
+
+    /// What pressing `key`, as given by `KeyboardEvent.key`, does
+    pub fn action(&self, cues: &[CueDefinition], key: &str) -> Option<KeyAction> {
+        let key = normalize_key(key);
+        self.bindings(cues)
+            .into_iter()
+            .find(|(bound, _)| *bound == key)
+            .map(|(_, action)| action)
+    }
+
+    /// The first key that is still bound more than once, if any, i.e. that two cues or buttons were both configured
+    /// with
+    pub fn duplicate(&self, cues: &[CueDefinition]) -> Option<String> {
+        let bindings = self.bindings(cues);
+        bindings
+            .iter()
+            .enumerate()
+            .find(|(index, (key, _))| bindings[..*index].iter().any(|(other, _)| other == key))
+            .map(|(_, (key, _))| display_key(key))
+    }
+}
+
+/// Single characters in lower case, and `Space` as the space character that browsers report
+pub fn normalize_key(key: &str) -> String {
+    if key.eq_ignore_ascii_case("space") {
+        return " ".to_string();
+    }
+    let mut chars = key.chars();
+    match (chars.next(), chars.next()) {
+        (Some(ch), None) => ch.to_lowercase().collect(),
+        _ => key.to_string(),
+    }
+}
+
+/// How a normalized key is shown in the legend
+pub fn display_key(key: &str) -> String {
+    match key {
+        " " => "Space".to_string(),
+        key if key.chars().count() == 1 => key.to_uppercase(),
+        key => key.to_string(),
+    }
+}
+
+/// Calls `on_key` with every key pressed on the page for as long as `cx` lives, except while typing into a form
+/// field or holding a modifier, so that browser shortcuts keep working. Keys that `on_key` handles don't do what
+/// they would otherwise, such as scrolling the page.
+pub fn on_shortcut_key(cx: Scope, on_key: impl Fn(&str) -> bool + 'static) {
+    cfg_if::cfg_if! {
+        if #[cfg(not(feature = "ssr"))] {
+            use wasm_bindgen::closure::Closure;
+            use wasm_bindgen::JsCast;
+            use web_sys::{Element, KeyboardEvent};
+
+            let listener = Closure::<dyn Fn(KeyboardEvent)>::new(move |ev: KeyboardEvent| {
+                // Held pedals repeat
+                if ev.ctrl_key() || ev.meta_key() || ev.alt_key() || ev.repeat() {
+                    return;
+                }
+                let typing = ev
+                    .target()
+                    .and_then(|target| target.dyn_into::<Element>().ok())
+                    .is_some_and(|element| element.matches("input:not([type=checkbox]), select, textarea").unwrap_or(false));
+                if !typing && on_key(&ev.key()) {
+                    ev.prevent_default();
+                }
+            });
+            let window = window();
+            if let Err(err) = window.add_event_listener_with_callback("keydown", listener.as_ref().unchecked_ref()) {
+                warn!("Could not listen to the keyboard: {:?}", err);
+                return;
+            }
+            on_cleanup(cx, move || {
+                let _ = window.remove_event_listener_with_callback("keydown", listener.as_ref().unchecked_ref());
+            });
+        } else {
+            let _ = (cx, on_key);
+        }
+    }
+}
+
+/// Whether this device uses the keyboard shortcuts, which it does unless turned off. Always true on the server.
+pub fn shortcuts_enabled() -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(not(feature = "ssr"))] {
+            let item = window()
+                .local_storage()
+                .ok()
+                .flatten()
+                .and_then(|storage| storage.get_item(ENABLED_ITEM).ok().flatten());
+            item.as_deref() != Some("off")
+        } else {
+            true
+        }
+    }
+}
+
+/// Remembers whether this device uses the keyboard shortcuts
+pub fn set_shortcuts_enabled(enabled: bool) {
+    cfg_if::cfg_if! {
+        if #[cfg(not(feature = "ssr"))] {
+            let storage = window().local_storage().ok().flatten();
+            let result = storage.map(|storage| storage.set_item(ENABLED_ITEM, if enabled { "on" } else { "off" }));
+            if let Some(Err(err)) = result {
+                warn!("Could not save keyboard setting: {:?}", err);
+            }
+        } else {
+            let _ = enabled;
+        }
+    }
+}
+
+/// The keyboard shortcuts of the director page, and a switch to turn them on or off on this device
+#[component]
+pub fn KeyLegend(
+    cx: Scope,
+    bindings: Vec<(String, KeyAction)>,
+    cues: Vec<CueDefinition>,
+    enabled: ReadSignal<bool>,
+    set_enabled: WriteSignal<bool>,
+) -> impl IntoView {
+    // Keys that do the same are listed together
+    let mut rows: Vec<(KeyAction, Vec<String>)> = Vec::new();
+    for (key, action) in bindings {
+        match rows.iter_mut().find(|(other, _)| *other == action) {
+            Some((_, keys)) => keys.push(display_key(&key)),
+            None => rows.push((action, vec![display_key(&key)])),
+        }
+    }
+    let rows = rows
+        .into_iter()
+        .map(|(action, keys)| {
+            view! { cx,
+                <tr>
+                    <td class="key-names">{keys.join(", ")}</td>
+                    <td>{action.description(&cues)}</td>
+                </tr>
+            }
+        })
+        .collect_view(cx);
+    view! { cx,
+        <label class="key-toggle">
+            <input
+                type="checkbox"
+                prop:checked=enabled
+                on:change=move |ev| {
+                    let checked = event_target_checked(&ev);
+                    set_shortcuts_enabled(checked);
+                    set_enabled.set(checked);
+                }
+            />
+            " Use keyboard and pedal on this device"
+        </label>
+        <table class="key-legend" class:disabled=move || !enabled.get()>{rows}</table>
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(code: char, label: &str, key: Option<&str>) -> CueDefinition {
+        CueDefinition {
+            code,
+            label: label.to_string(),
+            color: None,
+            row: None,
+            column: None,
+            numbers: 0,
+            key: key.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn cue_codes_override_default_keys() {
+        let keys = KeyBindings::default();
+        let cues = [cue('V', "Verse", None), cue('S', "Solo", None)];
+        assert_eq!(keys.action(&cues, "s"), Some(KeyAction::Cue('S')));
+        assert_eq!(keys.action(&cues, "S"), Some(KeyAction::Cue('S')));
+        assert_eq!(keys.action(&cues, "v"), Some(KeyAction::Cue('V')));
+        assert_eq!(keys.action(&cues, "Enter"), Some(KeyAction::Go));
+        assert_eq!(keys.overridden(&cues), [("S".to_string(), KeyAction::Stage, KeyAction::Cue('S'))]);
+        assert_eq!(keys.duplicate(&cues), None);
+
+        // Without the cue, the default key works again
+        assert_eq!(keys.action(&cues[..1], "s"), Some(KeyAction::Stage));
+        assert_eq!(keys.overridden(&cues[..1]), []);
+    }
+
+    #[test]
+    fn configured_keys_override_cue_codes() {
+        let keys = KeyBindings {
+            go: vec!["g".to_string()],
+            ..KeyBindings::default()
+        };
+        let cues = [cue('G', "Guitar", None), cue('Z', "Outro", Some("PageDown"))];
+        assert_eq!(keys.action(&cues, "g"), Some(KeyAction::Go));
+        assert_eq!(keys.action(&cues, "PageDown"), Some(KeyAction::Cue('Z')));
+        // The cue's code isn't bound when it has a key of its own
+        assert_eq!(keys.action(&cues, "z"), Some(KeyAction::Undo));
+        assert_eq!(
+            keys.overridden(&cues),
+            [
+                ("G".to_string(), KeyAction::Cue('G'), KeyAction::Go),
+                ("PageDown".to_string(), KeyAction::Next, KeyAction::Cue('Z')),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_keys_configured_twice() {
+        let keys = KeyBindings::default();
+        let cues = [cue('V', "Verse", Some("space")), cue('C', "Chorus", Some("Space"))];
+        assert_eq!(keys.duplicate(&cues), Some("Space".to_string()));
+        assert_eq!(keys.duplicate(&[cue('V', "Verse", Some("Space"))]), None);
+    }
+}
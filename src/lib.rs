@@ -1,7 +1,30 @@
 use cfg_if::cfg_if;
 pub mod app;
+pub mod audience;
+pub mod auth;
+pub mod chordpro;
+pub mod config;
+pub mod connection;
+pub mod control;
+pub mod display;
 pub mod error_template;
+pub mod fallback;
 pub mod fileserv;
+pub mod keyboard;
+pub mod library;
+pub mod midi;
+pub mod obs;
+pub mod osc;
+pub mod persistence;
+pub mod presence;
+pub mod protocol;
+pub mod recorder;
+pub mod rest;
+pub mod rooms;
+pub mod setlist;
+pub mod tls;
+pub mod vocabulary;
+pub mod webhooks;
 
 cfg_if! { if #[cfg(feature = "hydrate")] {
     use leptos::*;
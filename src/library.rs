@@ -0,0 +1,211 @@
+use cfg_if::cfg_if;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{section_segments_to_string, SectionTuple};
+
+/// A song in the library, as imported from its chart
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LibrarySong {
+    /// Made from the title, e.g. `amazing-grace`, so that importing a chart again replaces the song
+    pub id: String,
+    pub title: String,
+    /// Musical key, e.g. `G` or `Bbm`
+    pub key: Option<String>,
+    /// Beats per minute
+    pub tempo: Option<u16>,
+    /// Every distinct section, in the order they first appear in the chart
+    pub sections: Vec<SongSection>,
+    /// Sections in the order the chart plays them, including repeated choruses
+    pub arrangement: Vec<SectionTuple>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SongSection {
+    pub section: SectionTuple,
+    /// Name of the section, e.g. `Verse 2`, as written in the chart if it names it
+    pub name: String,
+}
+
+impl LibrarySong {
+    /// The song in the setlist's format, e.g. `Amazing Grace: V1 C V2 C`
+    pub fn setlist_line(&self) -> String {
+        let arrangement: Vec<String> = self.arrangement.iter().map(section_segments_to_string).collect();
+        format!("{}: {}", self.title, arrangement.join(" "))
+    }
+
+    /// Whether any section of the song is cue `code`
+    pub fn uses_cue(&self, code: char) -> bool {
+        self.sections.iter().any(|section| section.section.0 == Some(code))
+    }
+}
+
+/// ID of the song titled `title`, its letters and digits in lower case with dashes between words
+pub fn song_id(title: &str) -> String {
+    title
+        .split(|ch: char| !ch.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use crate::config::LibraryConfig;
+    use crate::persistence::{write_atomic, PersistenceError};
+
+    /// Storage backend for the song library
+    pub trait SongStore: Send + Sync {
+        /// Every song, ordered by title
+        fn list(&self) -> Result<Vec<LibrarySong>, PersistenceError>;
+        fn get(&self, id: &str) -> Result<Option<LibrarySong>, PersistenceError>;
+        /// Adds the song, replacing any song with the same ID
+        fn save(&self, song: &LibrarySong) -> Result<(), PersistenceError>;
+        /// Returns `false` if there was no such song
+        fn delete(&self, id: &str) -> Result<bool, PersistenceError>;
+    }
+
+    /// Opens the configured library, or returns `None` if it is disabled
+    pub fn open_library(config: &LibraryConfig) -> Result<Option<Arc<dyn SongStore>>, PersistenceError> {
+        Ok(match config {
+            LibraryConfig::None => None,
+            LibraryConfig::Json { path } => Some(Arc::new(JsonSongStore::open(path.clone())?)),
+            #[cfg(feature = "sqlite")]
+            LibraryConfig::Sqlite { path } => Some(Arc::new(SqliteSongStore::open(path)?)),
+        })
+    }
+
+    fn sort_by_title(songs: &mut [LibrarySong]) {
+        songs.sort_by_cached_key(|song| song.title.to_lowercase());
+    }
+
+    /// Stores the library as a single JSON object keyed by song ID, replaced atomically on every change
+    pub struct JsonSongStore {
+        path: PathBuf,
+        songs: Mutex<BTreeMap<String, LibrarySong>>,
+    }
+
+    impl JsonSongStore {
+        pub fn open(path: PathBuf) -> Result<Self, PersistenceError> {
+            let songs = match File::open(&path) {
+                Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(err) => return Err(err.into()),
+            };
+
+            Ok(JsonSongStore {
+                path,
+                songs: Mutex::new(songs),
+            })
+        }
+
+        fn write(&self, songs: &BTreeMap<String, LibrarySong>) -> Result<(), PersistenceError> {
+            write_atomic(&self.path, &serde_json::to_vec_pretty(songs)?)?;
+            Ok(())
+        }
+    }
+
+    impl SongStore for JsonSongStore {
+        fn list(&self) -> Result<Vec<LibrarySong>, PersistenceError> {
+            let mut songs: Vec<LibrarySong> = self.songs.lock().expect("JSON library mutex poisoned").values().cloned().collect();
+            sort_by_title(&mut songs);
+            Ok(songs)
+        }
+
+        fn get(&self, id: &str) -> Result<Option<LibrarySong>, PersistenceError> {
+            Ok(self.songs.lock().expect("JSON library mutex poisoned").get(id).cloned())
+        }
+
+        fn save(&self, song: &LibrarySong) -> Result<(), PersistenceError> {
+            let mut songs = self.songs.lock().expect("JSON library mutex poisoned");
+            let previous = songs.insert(song.id.clone(), song.clone());
+            if let Err(err) = self.write(&songs) {
+                // Keep what's in memory the same as what's on disk
+                match previous {
+                    Some(previous) => songs.insert(song.id.clone(), previous),
+                    None => songs.remove(&song.id),
+                };
+                return Err(err);
+            }
+            Ok(())
+        }
+
+        fn delete(&self, id: &str) -> Result<bool, PersistenceError> {
+            let mut songs = self.songs.lock().expect("JSON library mutex poisoned");
+            let Some(previous) = songs.remove(id) else {
+                return Ok(false);
+            };
+            if let Err(err) = self.write(&songs) {
+                songs.insert(id.to_string(), previous);
+                return Err(err);
+            }
+            Ok(true)
+        }
+    }
+
+    cfg_if! { if #[cfg(feature = "sqlite")] {
+        use rusqlite::{Connection, OptionalExtension};
+
+        /// Stores each song as a JSON document in an SQLite table
+        pub struct SqliteSongStore {
+            connection: Mutex<Connection>,
+        }
+
+        impl SqliteSongStore {
+            pub fn open(path: &std::path::Path) -> Result<Self, PersistenceError> {
+                let connection = Connection::open(path)?;
+                connection.pragma_update(None, "journal_mode", "WAL")?;
+                connection.pragma_update(None, "synchronous", "FULL")?;
+                connection.execute(
+                    "CREATE TABLE IF NOT EXISTS library_song (id TEXT PRIMARY KEY, song TEXT NOT NULL)",
+                    (),
+                )?;
+
+                Ok(SqliteSongStore {
+                    connection: Mutex::new(connection),
+                })
+            }
+        }
+
+        impl SongStore for SqliteSongStore {
+            fn list(&self) -> Result<Vec<LibrarySong>, PersistenceError> {
+                let connection = self.connection.lock().expect("SQLite connection mutex poisoned");
+                let mut statement = connection.prepare("SELECT song FROM library_song")?;
+                let rows = statement.query_map((), |row| row.get::<_, String>(0))?;
+                let mut songs = Vec::new();
+                for song in rows {
+                    songs.push(serde_json::from_str(&song?)?);
+                }
+                sort_by_title(&mut songs);
+                Ok(songs)
+            }
+
+            fn get(&self, id: &str) -> Result<Option<LibrarySong>, PersistenceError> {
+                let connection = self.connection.lock().expect("SQLite connection mutex poisoned");
+                let song: Option<String> = connection
+                    .query_row("SELECT song FROM library_song WHERE id = ?1", (id,), |row| row.get(0))
+                    .optional()?;
+                Ok(song.map(|song| serde_json::from_str(&song)).transpose()?)
+            }
+
+            fn save(&self, song: &LibrarySong) -> Result<(), PersistenceError> {
+                let json = serde_json::to_string(song)?;
+                let connection = self.connection.lock().expect("SQLite connection mutex poisoned");
+                connection.execute(
+                    "INSERT INTO library_song (id, song) VALUES (?1, ?2) ON CONFLICT (id) DO UPDATE SET song = excluded.song",
+                    (&song.id, json),
+                )?;
+                Ok(())
+            }
+
+            fn delete(&self, id: &str) -> Result<bool, PersistenceError> {
+                let connection = self.connection.lock().expect("SQLite connection mutex poisoned");
+                Ok(connection.execute("DELETE FROM library_song WHERE id = ?1", (id,))? > 0)
+            }
+        }
+    }}
+}}
@@ -4,26 +4,42 @@ cfg_if! {
     if #[cfg(feature = "ssr")] {
         use std::net::SocketAddr;
         use std::sync::Arc;
+        use std::time::Duration;
 
-        use axum::extract::{ConnectInfo, Path, RawQuery, State, WebSocketUpgrade};
+        use axum::extract::{ConnectInfo, Path, Query, RawQuery, State, WebSocketUpgrade};
         use axum::response::{Response, IntoResponse};
         use axum::body::Body as AxumBody;
-        use http::{HeaderMap, Request};
+        use http::{HeaderMap, Request, StatusCode};
         use leptos::{provide_context, view};
         use leptos_axum::handle_server_fns_with_context;
-        use song_sequence_director::app::{App, AppState, SectionTuple, section_socket};
+        use song_sequence_director::app::{App, AppState, section_socket, SocketClient, SocketQuery};
+        use song_sequence_director::auth::{require_director, Auth};
+        use song_sequence_director::protocol::SUBPROTOCOLS;
+        use song_sequence_director::rooms::{RoomRegistry, DEFAULT_ROOM};
+        use tower_http::compression::predicate::{DefaultPredicate, NotForContentType, Predicate};
         use tower_http::compression::CompressionLayer;
 
         #[tokio::main]
         async fn main() {
             use axum::{
+                middleware,
                 routing::{get, post},
                 Router,
             };
             use leptos::*;
             use leptos_axum::{generate_route_list, LeptosRoutes};
             use song_sequence_director::app::*;
+            use song_sequence_director::config::Config;
+            use song_sequence_director::persistence::open_store;
+            use song_sequence_director::recorder::{export_handler, CueLog};
+            use song_sequence_director::rest;
+            use song_sequence_director::fallback::{events_handler, poll_handler, send_handler};
             use song_sequence_director::fileserv::get_file_and_error_service;
+            use song_sequence_director::library::open_library;
+            use song_sequence_director::obs::Obs;
+            use song_sequence_director::osc::Osc;
+            use song_sequence_director::tls::rustls_config;
+            use song_sequence_director::webhooks::Webhooks;
             use std::net::SocketAddr;
 
             simple_logger::init_with_level(log::Level::Debug).expect("couldn't initialize logging");
@@ -38,49 +54,126 @@ cfg_if! {
             let addr = leptos_options.site_addr;
             let routes = generate_route_list(|cx| view! { cx, <App/> }).await;
 
-            let (section_tx, section_rx) = tokio::sync::watch::channel((None, None));
+            let config = Config::load().expect("couldn't load configuration");
+            let store = open_store(&config.persistence).expect("couldn't open state store");
+            let rooms = Arc::new(RoomRegistry::new(store));
+            let library = open_library(&config.library).expect("couldn't open song library");
+            tokio::spawn(rooms.clone().close_idle_rooms(Duration::from_secs(config.rooms.idle_timeout)));
+            let cue_log = config.recorder.enabled.then(|| Arc::new(CueLog::new(config.recorder.path.clone())));
+            if let Some(cue_log) = &cue_log {
+                tokio::spawn(cue_log.clone().record(rooms.subscribe_changes()));
+            }
+            let vocabulary = Arc::new(config.vocabulary);
+            if let Some(osc_config) = config.osc {
+                if config.auth.pin.is_some() && osc_config.allow.iter().any(|ip| !ip.is_loopback()) {
+                    log::warn!("OSC commands from {:?} can change the cues without the director PIN", osc_config.allow);
+                }
+                let osc = Arc::new(
+                    Osc::bind(osc_config, rooms.clone(), vocabulary.clone())
+                        .await
+                        .expect("couldn't set up OSC"),
+                );
+                tokio::spawn(osc.clone().send_changes(rooms.subscribe_changes()));
+                tokio::spawn(osc.receive_commands());
+            }
+            if let Some(obs_config) = config.obs {
+                let obs = Obs::new(obs_config, vocabulary.clone()).expect("couldn't set up OBS");
+                tokio::spawn(obs.run(rooms.subscribe_changes()));
+            }
+            if !config.webhooks.targets.is_empty() {
+                let webhooks = Arc::new(Webhooks::start(config.webhooks, vocabulary.clone()).expect("couldn't set up webhooks"));
+                tokio::spawn(webhooks.dispatch(rooms.subscribe_changes()));
+            }
+            // Kept until the server stops, as dropping it closes the ports
+            #[cfg(feature = "midi")]
+            let _midi = config.midi.and_then(|midi_config| {
+                song_sequence_director::midi::Midi::start(midi_config, rooms.clone(), vocabulary.clone())
+                    .map_err(|err| log::error!("MIDI is disabled: {}", err))
+                    .ok()
+            });
+            let auth = Arc::new(Auth::new(config.auth, config.tls.is_some()));
+            if !auth.is_enabled() {
+                log::warn!("No director PIN is configured, so anyone can change the cues");
+            }
             let app_state = AppState {
                 leptos_options: leptos_options.clone(),
-                section_tx: Arc::new(section_tx),
-                section_rx
+                rooms,
+                vocabulary,
+                display_profiles: Arc::new(config.display_profiles),
+                library,
+                cue_log,
+                auth,
             };
 
             // build our application with a route
             let app = Router::new()
+                .route("/export", get(export_handler))
+                .leptos_routes_with_handler(routes, get(leptos_routes_handler))
+                // Only applies to the routes above. Server functions check access themselves.
+                .route_layer(middleware::from_fn_with_state(app_state.auth.clone(), require_director))
                 .route("/ws", get(ws_handler))
+                .route("/ws/events", get(events_handler))
+                .route("/ws/poll", get(poll_handler))
+                .route("/ws/send", post(send_handler))
+                .route("/api/v1/openapi.json", get(rest::openapi_handler))
+                .route("/api/v1/login", post(rest::post_login))
+                .route("/api/v1/rooms/:room/state", get(rest::get_state))
+                .route("/api/v1/rooms/:room/history", get(rest::get_history))
+                .route("/api/v1/rooms/:room/:action", post(rest::post_action))
                 .route("/api/*fn_name", post(server_fn_handler))
-                .leptos_routes_with_handler(routes, get(leptos_routes_handler))
                 .with_state(app_state)
-                .layer(CompressionLayer::new())
+                // Compressing would hold server-sent events back until enough of them have been buffered
+                .layer(CompressionLayer::new().compress_when(DefaultPredicate::new().and(NotForContentType::const_new("text/event-stream"))))
                 .fallback_service(get_file_and_error_service(&leptos_options));
 
-            // run our app with hyper
-            // `axum::Server` is a re-export of `hyper::Server`
-            log!("song director server v{} listening on http://{}", env!("CARGO_PKG_VERSION"), &addr);
-            axum::Server::bind(&addr)
-                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-                .await
-                .unwrap();
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            match config.tls {
+                Some(tls) => {
+                    let tls = rustls_config(&tls).await.expect("couldn't set up TLS");
+                    log!("song director server v{} listening on https://{}", env!("CARGO_PKG_VERSION"), &addr);
+                    axum_server::bind_rustls(addr, tls).serve(app).await.unwrap();
+                }
+                None => {
+                    // run our app with hyper
+                    // `axum::Server` is a re-export of `hyper::Server`
+                    log!("song director server v{} listening on http://{}", env!("CARGO_PKG_VERSION"), &addr);
+                    axum::Server::bind(&addr).serve(app).await.unwrap();
+                }
+            }
         }
 
-        async fn server_fn_handler(State(app_state): State<AppState>, path: Path<String>, headers: HeaderMap, raw_query: RawQuery, request: Request<AxumBody>) -> impl IntoResponse {
+        async fn server_fn_handler(State(app_state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, path: Path<String>, headers: HeaderMap, raw_query: RawQuery, request: Request<AxumBody>) -> impl IntoResponse {
             handle_server_fns_with_context(path, headers, raw_query, move |cx| {
-                provide_context(cx, app_state.section_tx.clone());
-                provide_context(cx, app_state.section_rx.clone());
+                provide_context(cx, app_state.rooms.clone());
+                provide_context(cx, app_state.vocabulary.clone());
+                provide_context(cx, app_state.display_profiles.clone());
+                provide_context(cx, app_state.library.clone());
+                provide_context(cx, app_state.auth.clone());
+                provide_context(cx, ConnectInfo(addr));
             }, request).await
         }
 
-        async fn leptos_routes_handler(State(app_state): State<AppState>, req: Request<AxumBody>) -> Response {
+        async fn leptos_routes_handler(State(app_state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, req: Request<AxumBody>) -> Response {
             let handler = leptos_axum::render_app_to_stream_with_context(app_state.leptos_options.clone(), move |cx| {
-                provide_context(cx, app_state.section_tx.clone());
-                provide_context(cx, app_state.section_rx.clone());
+                provide_context(cx, app_state.rooms.clone());
+                provide_context(cx, app_state.vocabulary.clone());
+                provide_context(cx, app_state.display_profiles.clone());
+                provide_context(cx, app_state.library.clone());
+                provide_context(cx, app_state.auth.clone());
+                provide_context(cx, ConnectInfo(addr));
             }, |cx| view! { cx, <App/> });
 
             handler(req).await.into_response()
         }
 
-        async fn ws_handler(State(section_rx): State<tokio::sync::watch::Receiver<SectionTuple>>, ws: WebSocketUpgrade, ConnectInfo(socket_addr): ConnectInfo<SocketAddr>) -> impl IntoResponse {
-            ws.on_upgrade(move |socket| section_socket(socket, section_rx, socket_addr))
+        async fn ws_handler(State(rooms): State<Arc<RoomRegistry>>, State(auth): State<Arc<Auth>>, Query(query): Query<SocketQuery>, headers: HeaderMap, ws: WebSocketUpgrade, ConnectInfo(socket_addr): ConnectInfo<SocketAddr>) -> Response {
+            let room = match rooms.get_or_create(query.room.as_deref().unwrap_or(DEFAULT_ROOM)) {
+                Ok(room) => room,
+                Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+            };
+            let client = SocketClient::new(socket_addr, query.device, &auth, &headers);
+            ws.protocols(SUBPROTOCOLS)
+                .on_upgrade(move |socket| section_socket(socket, room, client))
         }
     }
 }
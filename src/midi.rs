@@ -0,0 +1,314 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "midi")] {
+    use std::sync::Arc;
+
+    use midir::{MidiIO, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+    use serde::Deserialize;
+    use thiserror::Error;
+    use tokio::sync::broadcast::{self, error::RecvError};
+    use tokio::sync::mpsc;
+
+    use crate::app::section_segments_to_string;
+    use crate::config::MidiConfig;
+    use crate::control::{apply_action, ControlAction};
+    use crate::rooms::{CueChange, RoomRegistry};
+    use crate::vocabulary::Vocabulary;
+
+    /// Name of the ports the server opens, as shown by e.g. `aconnect -l`
+    const PORT_NAME: &str = "cues";
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum MidiMessageKind {
+        Note,
+        ControlChange,
+        ProgramChange,
+    }
+
+    /// A received MIDI message that triggers an action
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct MidiTrigger {
+        pub message: MidiMessageKind,
+        /// From 1 to 16. Messages on any channel match if left out.
+        pub channel: Option<u8>,
+        /// Note, controller or program number
+        pub number: u8,
+        /// Velocity or controller value that must match. By default any note on or non-zero controller value
+        /// matches, so that releasing a pedal does nothing.
+        pub value: Option<u8>,
+        pub action: ControlAction,
+        /// Cue for the `set` and `stage` actions, e.g. `V2`
+        pub cue: Option<String>,
+    }
+
+    /// A MIDI message sent when the current cue changes
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct MidiNotification {
+        /// Only sent when this cue is set, e.g. `V2`. Sent on every change if left out.
+        pub cue: Option<String>,
+        pub message: MidiMessageKind,
+        #[serde(default = "default_channel")]
+        pub channel: u8,
+        pub number: u8,
+        /// Note velocity or controller value
+        #[serde(default = "default_value")]
+        pub value: u8,
+    }
+
+    fn default_channel() -> u8 {
+        1
+    }
+
+    fn default_value() -> u8 {
+        127
+    }
+
+    #[derive(Debug, Error)]
+    pub enum MidiError {
+        #[error("could not open MIDI: {0}")]
+        Init(#[from] midir::InitError),
+        #[error("no MIDI {0} port named like \"{1}\"")]
+        PortNotFound(&'static str, String),
+        #[error("could not connect MIDI {0} port: {1}")]
+        Connect(&'static str, String),
+        #[error("MIDI channel {0} is not between 1 and 16")]
+        InvalidChannel(u8),
+        #[error("MIDI value {0} is above 127")]
+        InvalidValue(u8),
+        #[error("this system can't open virtual MIDI ports, so set `{0}` in [midi]")]
+        NoVirtualPorts(&'static str),
+    }
+
+    /// A channel message received from the input port
+    struct Received {
+        kind: MidiMessageKind,
+        channel: u8,
+        number: u8,
+        value: u8,
+    }
+
+    impl Received {
+        /// Note offs, which many controllers send as note ons with velocity 0, and other messages are ignored
+        fn parse(bytes: &[u8]) -> Option<Self> {
+            let (&status, data) = bytes.split_first()?;
+            let kind = match (status & 0xF0, data) {
+                (0x90, &[_, value, ..]) if value > 0 => MidiMessageKind::Note,
+                (0xB0, &[_, _, ..]) => MidiMessageKind::ControlChange,
+                (0xC0, &[_, ..]) => MidiMessageKind::ProgramChange,
+                _ => return None,
+            };
+            Some(Received {
+                kind,
+                channel: (status & 0x0F) + 1,
+                number: data[0],
+                value: data.get(1).copied().unwrap_or(0),
+            })
+        }
+    }
+
+    impl MidiTrigger {
+        fn matches(&self, received: &Received) -> bool {
+            self.message == received.kind
+                && self.channel.map_or(true, |channel| channel == received.channel)
+                && self.number == received.number
+                && match self.value {
+                    Some(value) => value == received.value,
+                    None => received.kind == MidiMessageKind::ProgramChange || received.value > 0,
+                }
+        }
+
+        fn check(&self) -> Result<(), MidiError> {
+            check_message(self.channel, &[Some(self.number), self.value])
+        }
+    }
+
+    impl MidiNotification {
+        fn matches(&self, change: &CueChange) -> bool {
+            self.cue
+                .as_ref()
+                .map_or(true, |cue| *cue == section_segments_to_string(&change.section))
+        }
+
+        fn send(&self, connection: &mut MidiOutputConnection) -> Result<(), midir::SendError> {
+            let channel = self.channel - 1;
+            match self.message {
+                MidiMessageKind::Note => {
+                    connection.send(&[0x90 | channel, self.number, self.value])?;
+                    connection.send(&[0x80 | channel, self.number, 0])
+                }
+                MidiMessageKind::ControlChange => connection.send(&[0xB0 | channel, self.number, self.value]),
+                MidiMessageKind::ProgramChange => connection.send(&[0xC0 | channel, self.number]),
+            }
+        }
+
+        fn check(&self) -> Result<(), MidiError> {
+            check_message(Some(self.channel), &[Some(self.number), Some(self.value)])
+        }
+    }
+
+    fn check_message(channel: Option<u8>, data: &[Option<u8>]) -> Result<(), MidiError> {
+        if let Some(channel) = channel.filter(|channel| !(1..=16).contains(channel)) {
+            return Err(MidiError::InvalidChannel(channel));
+        }
+        if let Some(value) = data.iter().flatten().find(|value| **value > 127) {
+            return Err(MidiError::InvalidValue(*value));
+        }
+        Ok(())
+    }
+
+    /// The first port whose name contains `name`
+    fn find_port<T: MidiIO>(io: &T, name: &str) -> Option<T::Port> {
+        io.ports()
+            .into_iter()
+            .find(|port| io.port_name(port).is_ok_and(|port_name| port_name.contains(name)))
+    }
+
+    /// The connections to the MIDI ports, which stay open until this is dropped
+    pub struct Midi {
+        _input: Option<MidiInputConnection<()>>,
+    }
+
+    impl Midi {
+        /// Connects to the configured ports, or opens virtual ports for other programs to connect to. The input
+        /// is only opened if there are triggers, and the output only if there are notifications. Must be called from
+        /// within the Tokio runtime.
+        pub fn start(config: MidiConfig, rooms: Arc<RoomRegistry>, vocabulary: Arc<Vocabulary>) -> Result<Self, MidiError> {
+            for trigger in &config.receive {
+                trigger.check()?;
+            }
+            for notification in &config.send {
+                notification.check()?;
+            }
+            if !config.send.is_empty() {
+                let output = MidiOutput::new(&config.client_name)?;
+                let connection = match &config.output {
+                    Some(name) => {
+                        let port = find_port(&output, name).ok_or_else(|| MidiError::PortNotFound("output", name.clone()))?;
+                        output
+                            .connect(&port, PORT_NAME)
+                            .map_err(|err| MidiError::Connect("output", err.to_string()))?
+                    }
+                    None => virtual_output(output)?,
+                };
+                let changes = rooms.subscribe_changes();
+                let (room, notifications) = (config.room.clone(), config.send.clone());
+                std::thread::spawn(move || send_changes(connection, changes, room, notifications));
+            }
+            let input = if config.receive.is_empty() {
+                None
+            } else {
+                let input = MidiInput::new(&config.client_name)?;
+                let port = match &config.input {
+                    Some(name) => Some(find_port(&input, name).ok_or_else(|| MidiError::PortNotFound("input", name.clone()))?),
+                    None => None,
+                };
+                let source = match &port {
+                    Some(port) => format!("MIDI ({})", input.port_name(port).unwrap_or_default()),
+                    None => "MIDI".to_string(),
+                };
+                // The callback runs on the MIDI library's own thread, but rooms can only be opened within the runtime
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(apply_triggers(rx, config.receive, config.room, rooms, vocabulary, source));
+                let callback = move |_, bytes: &[u8], _: &mut ()| {
+                    if let Some(received) = Received::parse(bytes) {
+                        let _ = tx.send(received);
+                    }
+                };
+                let connection = match port {
+                    Some(port) => input
+                        .connect(&port, PORT_NAME, callback, ())
+                        .map_err(|err| MidiError::Connect("input", err.to_string()))?,
+                    None => virtual_input(input, callback)?,
+                };
+                Some(connection)
+            };
+            Ok(Midi { _input: input })
+        }
+    }
+
+    #[cfg(unix)]
+    fn virtual_output(output: MidiOutput) -> Result<MidiOutputConnection, MidiError> {
+        use midir::os::unix::VirtualOutput;
+
+        output
+            .create_virtual(PORT_NAME)
+            .map_err(|err| MidiError::Connect("output", err.to_string()))
+    }
+
+    #[cfg(not(unix))]
+    fn virtual_output(_output: MidiOutput) -> Result<MidiOutputConnection, MidiError> {
+        Err(MidiError::NoVirtualPorts("output"))
+    }
+
+    #[cfg(unix)]
+    fn virtual_input(
+        input: MidiInput,
+        callback: impl FnMut(u64, &[u8], &mut ()) + Send + 'static,
+    ) -> Result<MidiInputConnection<()>, MidiError> {
+        use midir::os::unix::VirtualInput;
+
+        input
+            .create_virtual(PORT_NAME, callback, ())
+            .map_err(|err| MidiError::Connect("input", err.to_string()))
+    }
+
+    #[cfg(not(unix))]
+    fn virtual_input(
+        _input: MidiInput,
+        _callback: impl FnMut(u64, &[u8], &mut ()) + Send + 'static,
+    ) -> Result<MidiInputConnection<()>, MidiError> {
+        Err(MidiError::NoVirtualPorts("input"))
+    }
+
+    /// Applies the triggers matching each message received until the input is closed
+    async fn apply_triggers(
+        mut received: mpsc::UnboundedReceiver<Received>,
+        triggers: Vec<MidiTrigger>,
+        room: String,
+        rooms: Arc<RoomRegistry>,
+        vocabulary: Arc<Vocabulary>,
+        source: String,
+    ) {
+        while let Some(received) = received.recv().await {
+            for trigger in triggers.iter().filter(|trigger| trigger.matches(&received)) {
+                let result = match rooms.get_or_create(&room) {
+                    Ok(room) => apply_action(&room, &vocabulary, trigger.action, trigger.cue.as_deref(), &source),
+                    Err(err) => {
+                        log::warn!("MIDI trigger: {}", err);
+                        break;
+                    }
+                };
+                if let Err(err) = result {
+                    log::warn!("MIDI trigger {:?} {}: {}", trigger.message, trigger.number, err);
+                }
+            }
+        }
+    }
+
+    /// Sends the notifications matching each cue change in `room` until the channel closes. MIDI output blocks, so
+    /// this runs on its own thread.
+    fn send_changes(
+        mut connection: MidiOutputConnection,
+        mut changes: broadcast::Receiver<CueChange>,
+        room: String,
+        notifications: Vec<MidiNotification>,
+    ) {
+        loop {
+            match changes.blocking_recv() {
+                Ok(change) if change.room == room => {
+                    for notification in notifications.iter().filter(|notification| notification.matches(&change)) {
+                        if let Err(err) = notification.send(&mut connection) {
+                            log::warn!("Could not send MIDI message: {}", err);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => log::warn!("MIDI missed {} cue changes", missed),
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}}
@@ -0,0 +1,547 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use futures::{SinkExt, StreamExt};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+    use thiserror::Error;
+    use tokio::net::TcpStream;
+    use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    use crate::app::section_segments_to_string;
+    use crate::config::ObsConfig;
+    use crate::connection::retry_delay;
+    use crate::rooms::CueChange;
+    use crate::vocabulary::Vocabulary;
+
+    /// WebSocket subprotocol of obs-websocket's JSON encoding
+    const SUBPROTOCOL: &str = "obswebsocket.json";
+    /// Version of the obs-websocket RPC this client speaks
+    const RPC_VERSION: u32 = 1;
+    /// Time OBS has to answer the handshake or a request
+    const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Message types of obs-websocket, sent as `op`
+    const OP_HELLO: u8 = 0;
+    const OP_IDENTIFY: u8 = 1;
+    const OP_IDENTIFIED: u8 = 2;
+    const OP_REQUEST: u8 = 6;
+    const OP_REQUEST_RESPONSE: u8 = 7;
+
+    type ObsSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// What OBS does when a matching cue is set
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct ObsRule {
+        /// Cue that triggers the rule, e.g. `V2`. A letter without a number, e.g. `B`, matches every number, and an
+        /// empty one matches clearing the cue. The rule applies to every change if left out.
+        pub cue: Option<String>,
+        /// Scene to switch the program output to
+        pub scene: Option<String>,
+        /// Sources to show and hide in their scenes
+        #[serde(default)]
+        pub show: Vec<ObsSceneItem>,
+        #[serde(default)]
+        pub hide: Vec<ObsSceneItem>,
+        /// Text sources to update
+        #[serde(default)]
+        pub text: Vec<ObsText>,
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct ObsSceneItem {
+        pub scene: String,
+        pub source: String,
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct ObsText {
+        pub source: String,
+        /// New text, where `{section}`, `{next}`, `{label}`, `{song}` and `{room}` are replaced by the new cue
+        pub text: String,
+    }
+
+    impl ObsRule {
+        fn matches(&self, change: &CueChange) -> bool {
+            let Some(cue) = &self.cue else {
+                return true;
+            };
+            let mut chars = cue.chars();
+            match (chars.next(), chars.next()) {
+                // Just a letter
+                (Some(code), None) => change.section.0 == Some(code),
+                _ => *cue == section_segments_to_string(&change.section),
+            }
+        }
+    }
+
+    #[derive(Debug, Error)]
+    pub enum ObsError {
+        #[error("invalid OBS URL \"{0}\": {1}")]
+        InvalidUrl(String, String),
+        #[error("could not connect to OBS: {0}")]
+        WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+        #[error("OBS asks for a password, but none is configured")]
+        PasswordRequired,
+        #[error("OBS did not answer in time")]
+        Timeout,
+        #[error("OBS closed the connection")]
+        Closed,
+        #[error("unexpected message from OBS: {0}")]
+        Protocol(String),
+        #[error("{0} failed: {1}")]
+        Request(&'static str, String),
+    }
+
+    impl From<tokio_tungstenite::tungstenite::Error> for ObsError {
+        fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+            ObsError::WebSocket(Box::new(err))
+        }
+    }
+
+    #[derive(Serialize)]
+    struct Outgoing<T> {
+        op: u8,
+        d: T,
+    }
+
+    #[derive(Deserialize)]
+    struct Incoming {
+        op: u8,
+        d: Value,
+    }
+
+    #[derive(Deserialize)]
+    struct Hello {
+        authentication: Option<AuthChallenge>,
+    }
+
+    #[derive(Deserialize)]
+    struct AuthChallenge {
+        challenge: String,
+        salt: String,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct RequestResponse {
+        request_id: String,
+        request_status: RequestStatus,
+        #[serde(default)]
+        response_data: Value,
+    }
+
+    #[derive(Deserialize)]
+    struct RequestStatus {
+        result: bool,
+        #[serde(default)]
+        comment: Option<String>,
+    }
+
+    /// The answer to obs-websocket's authentication challenge
+    fn authentication(password: &str, challenge: &AuthChallenge) -> String {
+        let secret = BASE64.encode(Sha256::digest(format!("{}{}", password, challenge.salt)));
+        BASE64.encode(Sha256::digest(format!("{}{}", secret, challenge.challenge)))
+    }
+
+    /// Drives OBS from the cue changes of one room, using the obs-websocket v5 protocol that OBS 28 and later include
+    pub struct Obs {
+        config: ObsConfig,
+        vocabulary: Arc<Vocabulary>,
+    }
+
+    impl Obs {
+        pub fn new(config: ObsConfig, vocabulary: Arc<Vocabulary>) -> Result<Self, ObsError> {
+            config
+                .url
+                .as_str()
+                .into_client_request()
+                .map_err(|err| ObsError::InvalidUrl(config.url.clone(), err.to_string()))?;
+            Ok(Obs { config, vocabulary })
+        }
+
+        /// Applies the rules matching each cue change in the configured room until the channel closes. Reconnects
+        /// with exponential backoff whenever OBS isn't reachable, and then applies the latest change missed meanwhile.
+        pub async fn run(self, mut changes: broadcast::Receiver<CueChange>) {
+            let mut failures = 0;
+            loop {
+                match self.connect().await {
+                    Ok(socket) => {
+                        log::info!("Connected to OBS at {}", self.config.url);
+                        failures = 0;
+                        let mut connection = Connection {
+                            socket,
+                            next_request_id: 0,
+                            scene_item_ids: HashMap::new(),
+                        };
+                        match self.follow_changes(&mut connection, &mut changes).await {
+                            Ok(()) => return,
+                            Err(err) => log::warn!("Lost connection to OBS: {}", err),
+                        }
+                    }
+                    Err(err) => log::warn!("{}", err),
+                }
+                failures += 1;
+                tokio::time::sleep(retry_delay(failures, rand::random())).await;
+            }
+        }
+
+        /// Opens the WebSocket and completes the handshake
+        async fn connect(&self) -> Result<ObsSocket, ObsError> {
+            let mut request = self
+                .config
+                .url
+                .as_str()
+                .into_client_request()
+                .map_err(|err| ObsError::InvalidUrl(self.config.url.clone(), err.to_string()))?;
+            request
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(SUBPROTOCOL));
+            let (mut socket, _) = tokio_tungstenite::connect_async(request).await?;
+
+            let hello: Hello = receive(&mut socket, OP_HELLO).await?;
+            let authentication = match (&hello.authentication, &self.config.password) {
+                (Some(challenge), Some(password)) => Some(authentication(password, challenge)),
+                (Some(_), None) => return Err(ObsError::PasswordRequired),
+                (None, _) => None,
+            };
+            let identify = json!({
+                "rpcVersion": RPC_VERSION,
+                "authentication": authentication,
+                // No events are needed, only answers to requests
+                "eventSubscriptions": 0,
+            });
+            send(&mut socket, OP_IDENTIFY, identify).await?;
+            let _: Value = receive(&mut socket, OP_IDENTIFIED).await?;
+            Ok(socket)
+        }
+
+        /// Applies changes until the channel closes, or returns the error that broke the connection
+        async fn follow_changes(
+            &self,
+            connection: &mut Connection,
+            changes: &mut broadcast::Receiver<CueChange>,
+        ) -> Result<(), ObsError> {
+            // Only the latest of the changes queued while disconnected matters
+            let mut missed = None;
+            loop {
+                match changes.try_recv() {
+                    Ok(change) if change.room == self.config.room => missed = Some(change),
+                    Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Closed) => return Ok(()),
+                }
+            }
+            if let Some(change) = missed {
+                self.apply(connection, &change).await?;
+            }
+            loop {
+                tokio::select! {
+                    change = changes.recv() => match change {
+                        Ok(change) if change.room == self.config.room => self.apply(connection, &change).await?,
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(missed)) => log::warn!("OBS missed {} cue changes", missed),
+                        Err(RecvError::Closed) => return Ok(()),
+                    },
+                    // Nothing is expected while idle, but reading notices the connection closing and answers pings
+                    message = connection.socket.next() => match message {
+                        Some(Ok(Message::Close(_))) | None => return Err(ObsError::Closed),
+                        Some(Ok(_)) => {}
+                        Some(Err(err)) => return Err(err.into()),
+                    },
+                }
+            }
+        }
+
+        /// Sends the requests of every rule matching `change`. A failed request is logged and the others still
+        /// sent, as it's usually a misspelt scene or source.
+        async fn apply(&self, connection: &mut Connection, change: &CueChange) -> Result<(), ObsError> {
+            for rule in self.config.rules.iter().filter(|rule| rule.matches(change)) {
+                if let Some(scene) = &rule.scene {
+                    log_failed(connection.request("SetCurrentProgramScene", json!({ "sceneName": scene })).await)?;
+                }
+                for (items, enabled) in [(&rule.show, true), (&rule.hide, false)] {
+                    for item in items {
+                        log_failed(connection.set_scene_item_enabled(item, enabled).await)?;
+                    }
+                }
+                for text in &rule.text {
+                    let settings = json!({
+                        "inputName": text.source,
+                        "inputSettings": { "text": change.fill_template(&text.text, &self.vocabulary) },
+                        "overlay": true,
+                    });
+                    log_failed(connection.request("SetInputSettings", settings).await)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Logs a failed request, passing on only the errors that break the connection
+    fn log_failed(result: Result<Value, ObsError>) -> Result<(), ObsError> {
+        match result {
+            Ok(_) => Ok(()),
+            Err(err @ ObsError::Request(..)) => {
+                log::warn!("OBS: {}", err);
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// An identified connection to OBS
+    struct Connection {
+        socket: ObsSocket,
+        next_request_id: u64,
+        /// IDs of sources in scenes, which OBS needs to show or hide them
+        scene_item_ids: HashMap<(String, String), i64>,
+    }
+
+    impl Connection {
+        /// Sends a request and waits for its answer, returning its data
+        async fn request(&mut self, request_type: &'static str, data: Value) -> Result<Value, ObsError> {
+            self.next_request_id += 1;
+            let request_id = self.next_request_id.to_string();
+            let request = json!({
+                "requestType": request_type,
+                "requestId": request_id,
+                "requestData": data,
+            });
+            send(&mut self.socket, OP_REQUEST, request).await?;
+            loop {
+                let response: RequestResponse = receive(&mut self.socket, OP_REQUEST_RESPONSE).await?;
+                // Answers to earlier requests that timed out are skipped
+                if response.request_id != request_id {
+                    continue;
+                }
+                return if response.request_status.result {
+                    Ok(response.response_data)
+                } else {
+                    Err(ObsError::Request(request_type, response.request_status.comment.unwrap_or_default()))
+                };
+            }
+        }
+
+        async fn set_scene_item_enabled(&mut self, item: &ObsSceneItem, enabled: bool) -> Result<Value, ObsError> {
+            let key = (item.scene.clone(), item.source.clone());
+            let id = match self.scene_item_ids.get(&key) {
+                Some(id) => *id,
+                None => {
+                    let data = json!({ "sceneName": item.scene, "sourceName": item.source });
+                    let response = self.request("GetSceneItemId", data).await?;
+                    let id = response["sceneItemId"]
+                        .as_i64()
+                        .ok_or_else(|| ObsError::Protocol(format!("no scene item ID in {}", response)))?;
+                    self.scene_item_ids.insert(key, id);
+                    id
+                }
+            };
+            let data = json!({ "sceneName": item.scene, "sceneItemId": id, "sceneItemEnabled": enabled });
+            self.request("SetSceneItemEnabled", data).await
+        }
+    }
+
+    async fn send(socket: &mut ObsSocket, op: u8, d: Value) -> Result<(), ObsError> {
+        let text = serde_json::to_string(&Outgoing { op, d }).map_err(|err| ObsError::Protocol(err.to_string()))?;
+        socket.send(Message::Text(text)).await?;
+        Ok(())
+    }
+
+    /// Waits for the next message of type `op`, skipping others such as events
+    async fn receive<T: for<'de> Deserialize<'de>>(socket: &mut ObsSocket, op: u8) -> Result<T, ObsError> {
+        let deadline = tokio::time::Instant::now() + ANSWER_TIMEOUT;
+        loop {
+            let message = tokio::time::timeout_at(deadline, socket.next())
+                .await
+                .map_err(|_| ObsError::Timeout)?;
+            let text = match message {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(_))) | None => return Err(ObsError::Closed),
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Err(err.into()),
+            };
+            let incoming: Incoming = serde_json::from_str(&text).map_err(|err| ObsError::Protocol(err.to_string()))?;
+            if incoming.op == op {
+                return serde_json::from_value(incoming.d).map_err(|err| ObsError::Protocol(err.to_string()));
+            }
+        }
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use chrono::Utc;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    const PASSWORD: &str = "hunter2";
+
+    fn challenge() -> AuthChallenge {
+        AuthChallenge {
+            challenge: "+IxH4CnCiqpX1rM9scsNynZzbOe4KhDeYcTNS3PDaeY=".to_string(),
+            salt: "lM1GncleQOaCu9lT1yeUZhFYnqhsLLP1G5lAGo3ixaI=".to_string(),
+        }
+    }
+
+    /// What the mock OBS receives
+    #[derive(Debug)]
+    enum Received {
+        Identify(Value),
+        Request(Value),
+    }
+
+    /// Accepts `connections` connections one after the other like obs-websocket does, asking for a password and
+    /// answering every request. Each connection is closed after `requests_per_connection` requests.
+    async fn mock_obs(
+        listener: TcpListener,
+        connections: usize,
+        requests_per_connection: usize,
+        received: mpsc::UnboundedSender<Received>,
+    ) {
+        for _ in 0..connections {
+            let (stream, _) = listener.accept().await.expect("mock OBS could not accept");
+            let mut socket = tokio_tungstenite::accept_async(stream).await.expect("mock OBS handshake failed");
+            let hello = json!({
+                "obsWebSocketVersion": "5.0.0",
+                "rpcVersion": RPC_VERSION,
+                "authentication": { "challenge": challenge().challenge, "salt": challenge().salt },
+            });
+            socket
+                .send(Message::Text(json!({ "op": OP_HELLO, "d": hello }).to_string()))
+                .await
+                .unwrap();
+            let identify = next_message(&mut socket).await;
+            received.send(Received::Identify(identify)).unwrap();
+            let identified = json!({ "op": OP_IDENTIFIED, "d": { "negotiatedRpcVersion": RPC_VERSION } });
+            socket.send(Message::Text(identified.to_string())).await.unwrap();
+
+            for _ in 0..requests_per_connection {
+                let request = next_message(&mut socket).await;
+                let response_data = match request["requestType"].as_str() {
+                    Some("GetSceneItemId") => json!({ "sceneItemId": 7 }),
+                    _ => Value::Null,
+                };
+                let response = json!({
+                    "op": OP_REQUEST_RESPONSE,
+                    "d": {
+                        "requestType": request["requestType"],
+                        "requestId": request["requestId"],
+                        "requestStatus": { "result": true, "code": 100 },
+                        "responseData": response_data,
+                    },
+                });
+                received.send(Received::Request(request)).unwrap();
+                socket.send(Message::Text(response.to_string())).await.unwrap();
+            }
+            socket.close(None).await.unwrap();
+        }
+    }
+
+    /// The `d` of the next message the client sends
+    async fn next_message(socket: &mut WebSocketStream<TcpStream>) -> Value {
+        loop {
+            match socket.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    let incoming: Incoming = serde_json::from_str(&text).unwrap();
+                    return incoming.d;
+                }
+                Some(Ok(_)) => continue,
+                other => panic!("mock OBS expected a message, got {:?}", other),
+            }
+        }
+    }
+
+    async fn next_received(received: &mut mpsc::UnboundedReceiver<Received>) -> Received {
+        tokio::time::timeout(Duration::from_secs(10), received.recv())
+            .await
+            .expect("mock OBS received nothing")
+            .expect("mock OBS stopped")
+    }
+
+    fn change(section: &str) -> CueChange {
+        CueChange {
+            room: "default".to_string(),
+            sequence: 1,
+            timestamp: Utc::now(),
+            section: crate::app::section_string_to_segments(section).unwrap(),
+            next: (None, None),
+            song: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_matching_rules_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (received_tx, mut received) = mpsc::unbounded_channel();
+        tokio::spawn(mock_obs(listener, 2, 3, received_tx));
+
+        let rule = |cue: &str, scene: &str| ObsRule {
+            cue: Some(cue.to_string()),
+            scene: Some(scene.to_string()),
+            show: vec![ObsSceneItem {
+                scene: scene.to_string(),
+                source: "Lyrics".to_string(),
+            }],
+            hide: Vec::new(),
+            text: Vec::new(),
+        };
+        let config = ObsConfig {
+            url: format!("ws://{}", addr),
+            password: Some(PASSWORD.to_string()),
+            rules: vec![rule("C", "Chorus"), rule("V2", "Verse")],
+            ..ObsConfig::default()
+        };
+        let (changes, changes_rx) = broadcast::channel(16);
+        let obs = Obs::new(config, Arc::new(Vocabulary::default())).unwrap();
+        tokio::spawn(obs.run(changes_rx));
+
+        let Received::Identify(identify) = next_received(&mut received).await else {
+            panic!("expected Identify first");
+        };
+        assert_eq!(identify["rpcVersion"], RPC_VERSION);
+        assert_eq!(identify["authentication"], authentication(PASSWORD, &challenge()));
+
+        changes.send(change("V2")).unwrap();
+        let mut requests = Vec::new();
+        for _ in 0..3 {
+            match next_received(&mut received).await {
+                Received::Request(request) => {
+                    requests.push((request["requestType"].clone(), request["requestData"].clone()))
+                }
+                other => panic!("expected a request, got {:?}", other),
+            }
+        }
+        assert_eq!(
+            requests,
+            [
+                (json!("SetCurrentProgramScene"), json!({ "sceneName": "Verse" })),
+                (json!("GetSceneItemId"), json!({ "sceneName": "Verse", "sourceName": "Lyrics" })),
+                (
+                    json!("SetSceneItemEnabled"),
+                    json!({ "sceneName": "Verse", "sceneItemId": 7, "sceneItemEnabled": true }),
+                ),
+            ]
+        );
+
+        // The mock closes the socket after those requests
+        assert!(matches!(next_received(&mut received).await, Received::Identify(_)));
+    }
+}
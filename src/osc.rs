@@ -0,0 +1,158 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use rosc::{OscMessage, OscPacket, OscType};
+    use thiserror::Error;
+    use tokio::net::UdpSocket;
+    use tokio::sync::broadcast::{self, error::RecvError};
+
+    use crate::config::OscConfig;
+    use crate::control::{apply_action, ControlError};
+    use crate::rooms::{CueChange, RoomError, RoomRegistry};
+    use crate::vocabulary::Vocabulary;
+
+    #[derive(Debug, Error)]
+    pub enum OscError {
+        #[error("could not open UDP socket on {0}: {1}")]
+        Bind(SocketAddr, std::io::Error),
+        #[error(transparent)]
+        Control(#[from] ControlError),
+        #[error(transparent)]
+        Room(#[from] RoomError),
+    }
+
+    /// Sends cue changes to OSC targets and applies the commands received over OSC, the same way as the director
+    /// page does
+    pub struct Osc {
+        config: OscConfig,
+        rooms: Arc<RoomRegistry>,
+        vocabulary: Arc<Vocabulary>,
+        socket: UdpSocket,
+    }
+
+    impl Osc {
+        /// Opens the UDP socket, on the configured address if listening for commands
+        pub async fn bind(config: OscConfig, rooms: Arc<RoomRegistry>, vocabulary: Arc<Vocabulary>) -> Result<Self, OscError> {
+            let addr = config.listen.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+            let socket = UdpSocket::bind(addr).await.map_err(|err| OscError::Bind(addr, err))?;
+            Ok(Osc {
+                config,
+                rooms,
+                vocabulary,
+                socket,
+            })
+        }
+
+        /// Sends a message to every target for each cue change in the configured room until the channel closes
+        pub async fn send_changes(self: Arc<Self>, mut changes: broadcast::Receiver<CueChange>) {
+            if self.config.targets.is_empty() {
+                return;
+            }
+            loop {
+                match changes.recv().await {
+                    Ok(change) if change.room == self.config.room => self.send_change(&change).await,
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => log::warn!("OSC missed {} cue changes", missed),
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        }
+
+        async fn send_change(&self, change: &CueChange) {
+            let args = self
+                .config
+                .send_args
+                .iter()
+                .map(|arg| OscType::String(change.fill_template(arg, &self.vocabulary)))
+                .collect();
+            let packet = OscPacket::Message(OscMessage {
+                addr: self.config.send_address.clone(),
+                args,
+            });
+            let bytes = match rosc::encoder::encode(&packet) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    log::error!("Could not encode OSC message: {}", err);
+                    return;
+                }
+            };
+            for target in &self.config.targets {
+                if let Err(err) = self.socket.send_to(&bytes, target).await {
+                    log::warn!("Could not send OSC message to {}: {}", target, err);
+                }
+            }
+        }
+
+        /// Applies the commands received on the listening address, forever
+        pub async fn receive_commands(self: Arc<Self>) {
+            if self.config.listen.is_none() {
+                return;
+            }
+            let mut buffer = vec![0; rosc::decoder::MTU];
+            loop {
+                let (length, sender) = match self.socket.recv_from(&mut buffer).await {
+                    Ok(received) => received,
+                    Err(err) => {
+                        log::warn!("Error receiving OSC message: {}", err);
+                        continue;
+                    }
+                };
+                if !self.config.allow.contains(&sender.ip()) {
+                    log::warn!("Ignoring OSC packet from {}, which isn't in the allowed addresses", sender);
+                    continue;
+                }
+                match rosc::decoder::decode_udp(&buffer[..length]) {
+                    Ok((_, packet)) => self.handle_packet(packet, sender),
+                    Err(err) => log::warn!("Invalid OSC packet from {}: {}", sender, err),
+                }
+            }
+        }
+
+        fn handle_packet(&self, packet: OscPacket, sender: SocketAddr) {
+            match packet {
+                OscPacket::Message(message) => {
+                    if let Err(err) = self.handle_message(&message, sender) {
+                        log::warn!("OSC message {} from {}: {}", message.addr, sender, err);
+                    }
+                }
+                OscPacket::Bundle(bundle) => {
+                    for packet in bundle.content {
+                        self.handle_packet(packet, sender);
+                    }
+                }
+            }
+        }
+
+        fn handle_message(&self, message: &OscMessage, sender: SocketAddr) -> Result<(), OscError> {
+            let Some(&action) = self.config.receive.get(&message.addr) else {
+                log::debug!("Ignoring OSC message {} from {}", message.addr, sender);
+                return Ok(());
+            };
+            if is_release(message.args.first()) {
+                return Ok(());
+            }
+            let room = self.rooms.get_or_create(&self.config.room)?;
+            let cue = match message.args.first() {
+                Some(OscType::String(cue)) => Some(cue.as_str()),
+                _ => None,
+            };
+            apply_action(&room, &self.vocabulary, action, cue, &format!("OSC ({})", sender.ip()))?;
+            Ok(())
+        }
+    }
+
+    /// Whether `arg` is a zero, which buttons such as TouchOSC's send when released after sending 1 when pressed
+    fn is_release(arg: Option<&OscType>) -> bool {
+        match arg {
+            Some(OscType::Int(value)) => *value == 0,
+            Some(OscType::Long(value)) => *value == 0,
+            Some(OscType::Float(value)) => *value == 0.0,
+            Some(OscType::Double(value)) => *value == 0.0,
+            Some(OscType::Bool(value)) => !value,
+            _ => false,
+        }
+    }
+}}
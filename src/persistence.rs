@@ -0,0 +1,163 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use thiserror::Error;
+
+    use crate::app::DirectorState;
+    use crate::config::PersistenceConfig;
+
+    #[derive(Debug, Error)]
+    pub enum PersistenceError {
+        #[error("I/O error: {0}")]
+        Io(#[from] std::io::Error),
+        #[error("could not (de)serialise state: {0}")]
+        Serde(#[from] serde_json::Error),
+        #[cfg(feature = "sqlite")]
+        #[error("SQLite error: {0}")]
+        Sqlite(#[from] rusqlite::Error),
+    }
+
+    /// Storage backend for the director state of each room
+    pub trait StateStore: Send + Sync {
+        /// Returns `None` if nothing has been saved for `room` yet
+        fn load(&self, room: &str) -> Result<Option<DirectorState>, PersistenceError>;
+        /// Replaces the saved state of `room`. A failed save must leave the previously saved state intact.
+        fn save(&self, room: &str, state: &DirectorState) -> Result<(), PersistenceError>;
+    }
+
+    /// Opens the configured store, or returns `None` if persistence is disabled
+    pub fn open_store(config: &PersistenceConfig) -> Result<Option<Arc<dyn StateStore>>, PersistenceError> {
+        Ok(match config {
+            PersistenceConfig::None => None,
+            PersistenceConfig::Json { path } => Some(Arc::new(JsonFileStore::open(path.clone())?)),
+            #[cfg(feature = "sqlite")]
+            PersistenceConfig::Sqlite { path } => Some(Arc::new(SqliteStore::open(path)?)),
+        })
+    }
+
+    /// Saves the state of `room` every time it changes, i.e. on every `set_section` and other director action,
+    /// until the room is closed
+    pub async fn persist_changes(store: Arc<dyn StateStore>, room: String, mut section_rx: tokio::sync::watch::Receiver<DirectorState>) {
+        while section_rx.changed().await.is_ok() {
+            let state = section_rx.borrow_and_update().clone();
+            let store = store.clone();
+            let save_room = room.clone();
+            match tokio::task::spawn_blocking(move || store.save(&save_room, &state)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => log::error!("Could not save state of room {}: {}", room, err),
+                Err(err) => log::error!("State save task for room {} failed: {}", room, err),
+            }
+        }
+    }
+
+    /// Stores the state of all rooms as a single JSON object keyed by room ID, replaced atomically on every save
+    pub struct JsonFileStore {
+        path: PathBuf,
+        rooms: Mutex<BTreeMap<String, DirectorState>>,
+    }
+
+    impl JsonFileStore {
+        pub fn open(path: PathBuf) -> Result<Self, PersistenceError> {
+            let rooms = match File::open(&path) {
+                Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(err) => return Err(err.into()),
+            };
+
+            Ok(JsonFileStore {
+                path,
+                rooms: Mutex::new(rooms),
+            })
+        }
+    }
+
+    impl StateStore for JsonFileStore {
+        fn load(&self, room: &str) -> Result<Option<DirectorState>, PersistenceError> {
+            Ok(self.rooms.lock().expect("JSON store mutex poisoned").get(room).cloned())
+        }
+
+        fn save(&self, room: &str, state: &DirectorState) -> Result<(), PersistenceError> {
+            // Hold the lock while writing so that concurrent saves of different rooms can't reorder
+            let mut rooms = self.rooms.lock().expect("JSON store mutex poisoned");
+            rooms.insert(room.to_string(), state.clone());
+            write_atomic(&self.path, &serde_json::to_vec_pretty(&*rooms)?)?;
+            Ok(())
+        }
+    }
+
+    /// Writes to a temporary file next to `path` and renames it over `path`, so that readers only ever see
+    /// either the old or the new contents even if power is lost part way through
+    pub fn write_atomic(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents)?;
+        // Make sure the contents are on disk before the rename makes them visible
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&temp_path, path)?;
+
+        // Persist the rename itself. Directories can't be opened as files on Windows, where the rename is
+        // already durable enough for our purposes.
+        #[cfg(unix)]
+        if let Some(dir) = path.parent() {
+            let dir = if dir.as_os_str().is_empty() { std::path::Path::new(".") } else { dir };
+            File::open(dir)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    cfg_if! { if #[cfg(feature = "sqlite")] {
+        use rusqlite::{Connection, OptionalExtension};
+
+        /// Stores the state of each room as a JSON document in an SQLite table
+        pub struct SqliteStore {
+            connection: Mutex<Connection>,
+        }
+
+        impl SqliteStore {
+            pub fn open(path: &std::path::Path) -> Result<Self, PersistenceError> {
+                let connection = Connection::open(path)?;
+                connection.pragma_update(None, "journal_mode", "WAL")?;
+                connection.pragma_update(None, "synchronous", "FULL")?;
+                connection.execute(
+                    "CREATE TABLE IF NOT EXISTS room_state (room TEXT PRIMARY KEY, state TEXT NOT NULL)",
+                    (),
+                )?;
+
+                Ok(SqliteStore {
+                    connection: Mutex::new(connection),
+                })
+            }
+        }
+
+        impl StateStore for SqliteStore {
+            fn load(&self, room: &str) -> Result<Option<DirectorState>, PersistenceError> {
+                let connection = self.connection.lock().expect("SQLite connection mutex poisoned");
+                let state: Option<String> = connection
+                    .query_row("SELECT state FROM room_state WHERE room = ?1", (room,), |row| row.get(0))
+                    .optional()?;
+                Ok(state.map(|state| serde_json::from_str(&state)).transpose()?)
+            }
+
+            fn save(&self, room: &str, state: &DirectorState) -> Result<(), PersistenceError> {
+                let state = serde_json::to_string(state)?;
+                let connection = self.connection.lock().expect("SQLite connection mutex poisoned");
+                connection.execute(
+                    "INSERT INTO room_state (room, state) VALUES (?1, ?2) ON CONFLICT (room) DO UPDATE SET state = excluded.state",
+                    (room, state),
+                )?;
+                Ok(())
+            }
+        }
+    }}
+}}
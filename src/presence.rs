@@ -0,0 +1,303 @@
+use cfg_if::cfg_if;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Role the director page registers with. Devices with any other role are expected to acknowledge cues.
+pub const DIRECTOR_ROLE: &str = "Director";
+
+/// A client connected to a room's cue socket, as shown to directors
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Device {
+    /// Unique among the devices connected to the server
+    pub id: u64,
+    pub name: String,
+    /// What the device is used for, e.g. `Drums` or `OBS`
+    pub role: String,
+    /// Groups whose targeted cues the device shows
+    pub groups: Vec<String>,
+    pub last_seen: DateTime<Utc>,
+    /// The device hasn't answered for a while, so it may have lost its connection without closing it
+    pub stale: bool,
+    /// Sequence number of the latest cue the device has acknowledged
+    pub acked: Option<u64>,
+}
+
+/// How a client introduces itself when connecting to `/ws`, as the `name`, `role` and `groups` query parameters
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registration {
+    pub name: Option<String>,
+    pub role: Option<String>,
+    /// Comma-separated groups whose targeted cues the client wants as well as the cues for everyone
+    pub groups: Option<String>,
+}
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use tokio::sync::watch;
+
+    /// Time between pings sent to every connected client
+    pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+    /// Silence after which a device is shown as stale
+    const STALE_AFTER: Duration = Duration::from_secs(40);
+    /// Silence after which the connection is given up on
+    const DISCONNECT_AFTER: Duration = Duration::from_secs(120);
+    /// Longer names and roles are cut off, so that they can't clutter the director's page
+    const MAX_NAME_LENGTH: usize = 40;
+    /// Devices using HTTP that one address can have listed in a room at once. Clients choose their keys themselves, so
+    /// without a limit anyone could fill the director's list.
+    const MAX_LEASES_PER_ADDRESS: usize = 16;
+
+    static NEXT_DEVICE_ID: AtomicU64 = AtomicU64::new(1);
+
+    /// The devices connected to a room
+    pub struct Presence {
+        devices_tx: watch::Sender<Vec<Device>>,
+        /// Devices getting updates over HTTP rather than a socket, by the key each of them chose
+        leases: Mutex<HashMap<String, Lease>>,
+    }
+
+    /// A device getting updates over HTTP, listed until it hasn't been heard from for a while
+    struct Lease {
+        id: u64,
+        addr: IpAddr,
+        last_seen: Instant,
+        /// Open event streams of the device, which keep it listed for as long as they are open
+        streams: usize,
+    }
+
+    impl Presence {
+        pub fn new() -> Self {
+            Presence {
+                devices_tx: watch::channel(Vec::new()).0,
+                leases: Mutex::new(HashMap::new()),
+            }
+        }
+
+        pub fn subscribe(&self) -> watch::Receiver<Vec<Device>> {
+            self.devices_tx.subscribe()
+        }
+
+        /// Adds a device, which stays listed until the returned handle is dropped
+        pub fn join(self: &Arc<Self>, name: String, role: String, groups: Vec<String>) -> DeviceHandle {
+            DeviceHandle {
+                presence: self.clone(),
+                id: self.add(name, role, groups),
+                last_seen: Instant::now(),
+            }
+        }
+
+        /// Lists the device at `addr` using `key`, or records that it has just been heard from if it is listed
+        /// already. Returns `false` without listing it if `addr` has too many devices listed already.
+        pub fn lease(&self, key: &str, addr: IpAddr, name: String, role: String, groups: Vec<String>) -> bool {
+            let mut leases = self.leases.lock().expect("lease mutex poisoned");
+            match leases.get_mut(key) {
+                Some(lease) => {
+                    lease.last_seen = Instant::now();
+                    self.seen(lease.id);
+                }
+                None => {
+                    if leases.values().filter(|lease| lease.addr == addr).count() >= MAX_LEASES_PER_ADDRESS {
+                        return false;
+                    }
+                    let lease = Lease {
+                        id: self.add(name, role, groups),
+                        addr,
+                        last_seen: Instant::now(),
+                        streams: 0,
+                    };
+                    leases.insert(key.to_string(), lease);
+                }
+            }
+            true
+        }
+
+        /// Keeps the device using `key` listed until the returned guard is dropped, e.g. while an event stream to
+        /// it is open. Returns `None` if it can't be listed, as for [`Presence::lease`].
+        pub fn hold(
+            self: &Arc<Self>,
+            key: &str,
+            addr: IpAddr,
+            name: String,
+            role: String,
+            groups: Vec<String>,
+        ) -> Option<LeaseGuard> {
+            if !self.lease(key, addr, name, role, groups) {
+                return None;
+            }
+            if let Some(lease) = self.leases.lock().expect("lease mutex poisoned").get_mut(key) {
+                lease.streams += 1;
+            }
+            Some(LeaseGuard {
+                presence: self.clone(),
+                key: key.to_string(),
+            })
+        }
+
+        /// Records that the device using `key` has acknowledged the cue set by update number `sequence`
+        pub fn ack_leased(&self, key: &str, sequence: u64) {
+            let id = self.leases.lock().expect("lease mutex poisoned").get(key).map(|lease| lease.id);
+            if let Some(id) = id {
+                self.ack(id, sequence);
+            }
+        }
+
+        /// Marks the devices using HTTP that have been silent for a while as stale, and removes those that have been
+        /// silent for too long
+        pub fn expire_leases(&self) {
+            self.leases.lock().expect("lease mutex poisoned").retain(|_, lease| {
+                let silence = lease.last_seen.elapsed();
+                if lease.streams > 0 {
+                    true
+                } else if silence >= DISCONNECT_AFTER {
+                    self.remove(lease.id);
+                    false
+                } else {
+                    if silence >= STALE_AFTER {
+                        self.update(lease.id, |device| !std::mem::replace(&mut device.stale, true));
+                    }
+                    true
+                }
+            });
+        }
+
+        fn add(&self, name: String, role: String, groups: Vec<String>) -> u64 {
+            let id = NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed);
+            self.devices_tx.send_modify(|devices| {
+                devices.push(Device {
+                    id,
+                    name: name.chars().take(MAX_NAME_LENGTH).collect(),
+                    role: role.chars().take(MAX_NAME_LENGTH).collect(),
+                    groups,
+                    last_seen: Utc::now(),
+                    stale: false,
+                    acked: None,
+                })
+            });
+            id
+        }
+
+        fn remove(&self, id: u64) {
+            self.devices_tx.send_modify(|devices| devices.retain(|device| device.id != id));
+        }
+
+        fn seen(&self, id: u64) {
+            self.update(id, |device| {
+                device.last_seen = Utc::now();
+                device.stale = false;
+                true
+            });
+        }
+
+        fn ack(&self, id: u64, sequence: u64) {
+            self.update(id, |device| device.acked.replace(sequence) != Some(sequence));
+        }
+
+        fn update(&self, id: u64, update: impl FnOnce(&mut Device) -> bool) {
+            self.devices_tx.send_if_modified(|devices| {
+                devices.iter_mut().find(|device| device.id == id).is_some_and(update)
+            });
+        }
+    }
+
+    impl Default for Presence {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    /// A connected device's entry in the [`Presence`] of its room
+    pub struct DeviceHandle {
+        presence: Arc<Presence>,
+        id: u64,
+        last_seen: Instant,
+    }
+
+    impl DeviceHandle {
+        /// Records that the device has just been heard from
+        pub fn seen(&mut self) {
+            self.last_seen = Instant::now();
+            self.presence.seen(self.id);
+        }
+
+        /// Records that the device has acknowledged the cue set by update number `sequence`
+        pub fn ack(&self, sequence: u64) {
+            self.presence.ack(self.id, sequence);
+        }
+
+        /// Marks the device as stale if it has been silent for a while. Returns `false` once it has been silent
+        /// for so long that its connection should be closed.
+        pub fn check(&self) -> bool {
+            let silence = self.last_seen.elapsed();
+            if silence >= STALE_AFTER {
+                self.presence.update(self.id, |device| !std::mem::replace(&mut device.stale, true));
+            }
+            silence < DISCONNECT_AFTER
+        }
+    }
+
+    impl Drop for DeviceHandle {
+        fn drop(&mut self) {
+            self.presence.remove(self.id);
+        }
+    }
+
+    /// An open event stream to a device using HTTP, see [`Presence::hold`]
+    pub struct LeaseGuard {
+        presence: Arc<Presence>,
+        key: String,
+    }
+
+    impl LeaseGuard {
+        /// Records that the device has just been heard from, or at least that its stream is still open
+        pub fn seen(&self) {
+            if let Some(lease) = self.presence.leases.lock().expect("lease mutex poisoned").get_mut(&self.key) {
+                lease.last_seen = Instant::now();
+                self.presence.seen(lease.id);
+            }
+        }
+    }
+
+    impl Drop for LeaseGuard {
+        fn drop(&mut self) {
+            let mut leases = self.presence.leases.lock().expect("lease mutex poisoned");
+            let Some(lease) = leases.get_mut(&self.key) else {
+                return;
+            };
+            lease.streams -= 1;
+            if lease.streams == 0 {
+                self.presence.remove(lease.id);
+                leases.remove(&self.key);
+            }
+        }
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    fn lease(presence: &Presence, key: &str, addr: IpAddr) -> bool {
+        presence.lease(key, addr, key.to_string(), String::new(), Vec::new())
+    }
+
+    #[test]
+    fn limits_leases_per_address() {
+        let presence = Presence::new();
+        let (first, second) = (IpAddr::from(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::from(Ipv4Addr::new(10, 0, 0, 2)));
+        for index in 0..MAX_LEASES_PER_ADDRESS {
+            assert!(lease(&presence, &format!("key{}", index), first));
+        }
+        assert!(!lease(&presence, "one-too-many", first));
+        // Devices already listed can still renew their lease, and other addresses are unaffected
+        assert!(lease(&presence, "key0", first));
+        assert!(lease(&presence, "other", second));
+        assert_eq!(presence.subscribe().borrow().len(), MAX_LEASES_PER_ADDRESS + 1);
+    }
+}
@@ -0,0 +1,287 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use std::collections::BTreeMap;
+
+use crate::app::SectionTuple;
+use crate::audience::TargetedCue;
+use crate::presence::Device;
+
+/// Version of the message format, sent with every message. Changes that older clients can't ignore need a new
+/// subprotocol as well.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const JSON_SUBPROTOCOL: &str = "song-director.v1.json";
+pub const CBOR_SUBPROTOCOL: &str = "song-director.v1.cbor";
+/// Subprotocols understood by the server, most preferred first. Clients that don't ask for any of them get the
+/// original format of bare strings such as `V2 → C`.
+pub const SUBPROTOCOLS: [&str; 2] = [CBOR_SUBPROTOCOL, JSON_SUBPROTOCOL];
+
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum ProtocolError {
+    #[error("could not encode message: {0}")]
+    Encode(String),
+    #[error("could not decode message: {0}")]
+    Decode(String),
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u32),
+}
+
+/// The current and staged cues of a room as of update number `sequence`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cues {
+    pub sequence: u64,
+    /// Number of the update that set the current cue, by which musicians acknowledge it
+    pub cue_sequence: u64,
+    pub section: SectionTuple,
+    pub next: SectionTuple,
+}
+
+impl Cues {
+    /// Replaces `known` with these cues unless `known` is from a later update. Returns whether it was replaced.
+    pub fn merge_into(self, known: &mut Option<Cues>) -> bool {
+        if known.is_some_and(|known| known.sequence > self.sequence) {
+            return false;
+        }
+        *known = Some(self);
+        true
+    }
+}
+
+/// Messages from the server to clients. Every update of a room's state increases its sequence number, which is
+/// sent with the messages about it. Numbers can be skipped when updates happen in quick succession.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// The complete cue state of the room, sent when a client connects
+    Snapshot {
+        sequence: u64,
+        cue_sequence: u64,
+        section: SectionTuple,
+        next: SectionTuple,
+    },
+    /// The current cue changed. `sequence` is the number of the update that set it, which identifies the cue.
+    Cue { sequence: u64, section: SectionTuple },
+    /// The staged next cue changed
+    NextCue { sequence: u64, section: SectionTuple },
+    /// The setlist, the position in it or the song loaded from the library changed. Clients fetch them themselves if
+    /// they need them.
+    SetlistChanged { sequence: u64 },
+    /// Text to show to the musicians. An empty text clears the previous one.
+    Message { text: String },
+    /// The devices connected to the room, sent to directors when a device joins, leaves or answers a ping
+    Devices { devices: Vec<Device> },
+    /// The cues for single groups, by group, sent to directors whenever they change
+    Targeted { cues: BTreeMap<String, TargetedCue> },
+    Ping { id: u64 },
+    Pong { id: u64 },
+    /// A message from the client couldn't be handled
+    Error { message: String },
+}
+
+impl ServerMessage {
+    /// Applies the message to the cues known to a client, ignoring changes from before the known update. A snapshot
+    /// always replaces the known cues, as the server starts numbering updates from 0 again when it restarts without
+    /// persistence or recreates a closed room. Changes are ignored until the cues are known. Returns whether the
+    /// current or staged cue changed.
+    pub fn apply_to(&self, known: &mut Option<Cues>) -> bool {
+        if let ServerMessage::Snapshot {
+            sequence,
+            cue_sequence,
+            section,
+            next,
+        } = *self
+        {
+            *known = Some(Cues {
+                sequence,
+                cue_sequence,
+                section,
+                next,
+            });
+            return true;
+        }
+        let Some(known) = known.as_mut() else {
+            return false;
+        };
+        match *self {
+            ServerMessage::Cue { sequence, section } if sequence > known.sequence => {
+                known.sequence = sequence;
+                known.cue_sequence = sequence;
+                known.section = section;
+                true
+            }
+            ServerMessage::NextCue { sequence, section } if sequence > known.sequence => {
+                known.sequence = sequence;
+                known.next = section;
+                true
+            }
+            ServerMessage::SetlistChanged { sequence } => {
+                known.sequence = known.sequence.max(sequence);
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Messages from clients to the server
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Ping { id: u64 },
+    Pong { id: u64 },
+    /// The musician has seen the cue set by update number `sequence`. Acknowledgements of earlier cues are ignored.
+    Ack { sequence: u64 },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<M> {
+    version: u32,
+    #[serde(flatten)]
+    message: M,
+}
+
+/// A WebSocket message, independent of the WebSocket implementation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Encoding negotiated through the WebSocket subprotocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// JSON in text messages
+    Json,
+    /// CBOR in binary messages
+    Cbor,
+}
+
+impl Encoding {
+    pub fn from_subprotocol(subprotocol: &str) -> Option<Self> {
+        match subprotocol {
+            JSON_SUBPROTOCOL => Some(Encoding::Json),
+            CBOR_SUBPROTOCOL => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn encode<M: Serialize>(self, message: &M) -> Result<Frame, ProtocolError> {
+        let envelope = Envelope {
+            version: PROTOCOL_VERSION,
+            message,
+        };
+        match self {
+            Encoding::Json => serde_json::to_string(&envelope)
+                .map(Frame::Text)
+                .map_err(|err| ProtocolError::Encode(err.to_string())),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(&envelope, &mut bytes).map_err(|err| ProtocolError::Encode(err.to_string()))?;
+                Ok(Frame::Binary(bytes))
+            }
+        }
+    }
+}
+
+/// Encodes `messages` as a JSON array, as answered by the HTTP fallbacks of the socket
+pub fn encode_json_batch<M: Serialize>(messages: &[M]) -> Result<String, ProtocolError> {
+    let envelopes: Vec<_> = messages
+        .iter()
+        .map(|message| Envelope {
+            version: PROTOCOL_VERSION,
+            message,
+        })
+        .collect();
+    serde_json::to_string(&envelopes).map_err(|err| ProtocolError::Encode(err.to_string()))
+}
+
+/// Decodes a JSON array of messages encoded by [`encode_json_batch`]
+pub fn decode_json_batch<M: DeserializeOwned>(text: &str) -> Result<Vec<M>, ProtocolError> {
+    let envelopes: Vec<Envelope<M>> = serde_json::from_str(text).map_err(|err| ProtocolError::Decode(err.to_string()))?;
+    envelopes
+        .into_iter()
+        .map(|envelope| {
+            if envelope.version == PROTOCOL_VERSION {
+                Ok(envelope.message)
+            } else {
+                Err(ProtocolError::UnsupportedVersion(envelope.version))
+            }
+        })
+        .collect()
+}
+
+/// Decodes a message in either encoding, telling them apart by the type of the frame
+pub fn decode<M: DeserializeOwned>(frame: &Frame) -> Result<M, ProtocolError> {
+    let envelope: Envelope<M> = match frame {
+        Frame::Text(text) => serde_json::from_str(text).map_err(|err| ProtocolError::Decode(err.to_string()))?,
+        Frame::Binary(bytes) => {
+            ciborium::de::from_reader(bytes.as_slice()).map_err(|err| ProtocolError::Decode(err.to_string()))?
+        }
+    };
+    if envelope.version != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(envelope.version));
+    }
+    Ok(envelope.message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+
+    fn section(code: char, number: usize) -> SectionTuple {
+        (Some(code), NonZeroUsize::new(number))
+    }
+
+    fn snapshot(sequence: u64, section: SectionTuple) -> ServerMessage {
+        ServerMessage::Snapshot {
+            sequence,
+            cue_sequence: sequence,
+            section,
+            next: (None, None),
+        }
+    }
+
+    #[test]
+    fn ignores_changes_older_than_the_known_cues() {
+        let mut known = None;
+        assert!(!ServerMessage::Cue { sequence: 1, section: section('V', 1) }.apply_to(&mut known));
+        assert_eq!(known, None);
+
+        assert!(snapshot(5, section('V', 1)).apply_to(&mut known));
+        assert!(!ServerMessage::Cue { sequence: 4, section: section('C', 0) }.apply_to(&mut known));
+        assert!(ServerMessage::Cue { sequence: 6, section: section('C', 0) }.apply_to(&mut known));
+        assert!(ServerMessage::NextCue { sequence: 7, section: section('B', 0) }.apply_to(&mut known));
+        assert_eq!(
+            known,
+            Some(Cues {
+                sequence: 7,
+                cue_sequence: 6,
+                section: section('C', 0),
+                next: section('B', 0),
+            })
+        );
+    }
+
+    #[test]
+    fn snapshot_with_a_lower_sequence_after_reconnect_replaces_the_cues() {
+        let mut known = None;
+        assert!(snapshot(40, section('V', 2)).apply_to(&mut known));
+
+        // The server restarted without persistence, so its sequence starts again
+        assert!(snapshot(0, (None, None)).apply_to(&mut known));
+        assert_eq!(known, Some(Cues::default()));
+        assert!(ServerMessage::Cue { sequence: 1, section: section('C', 0) }.apply_to(&mut known));
+        assert_eq!(known.map(|known| known.section), Some(section('C', 0)));
+    }
+
+    #[test]
+    fn merging_keeps_later_cues() {
+        let mut known = Some(Cues { sequence: 3, ..Cues::default() });
+        assert!(!Cues { sequence: 2, ..Cues::default() }.merge_into(&mut known));
+        assert!(Cues { sequence: 3, section: section('V', 1), ..Cues::default() }.merge_into(&mut known));
+        assert_eq!(known.map(|known| known.section), Some(section('V', 1)));
+    }
+}
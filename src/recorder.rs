@@ -0,0 +1,352 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use axum::extract::{Query, State};
+    use axum::response::{IntoResponse, Response};
+    use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+    use http::{header, StatusCode};
+    use serde::{Deserialize, Serialize};
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::broadcast::{self, error::RecvError};
+
+    use crate::app::section_segments_to_string;
+    use crate::rooms::{is_valid_room_id, CueChange, DEFAULT_ROOM};
+
+    /// Append-only log of every cue change in every room, stored as one JSON object per line
+    pub struct CueLog {
+        path: PathBuf,
+    }
+
+    impl CueLog {
+        pub fn new(path: PathBuf) -> Self {
+            CueLog { path }
+        }
+
+        /// Appends every cue change to the log until the channel closes
+        pub async fn record(self: Arc<Self>, mut changes: broadcast::Receiver<CueChange>) {
+            loop {
+                match changes.recv().await {
+                    Ok(change) => {
+                        if let Err(err) = self.append(&change).await {
+                            log::error!("Could not write cue change to {}: {}", self.path.display(), err);
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => log::warn!("Cue recorder missed {} changes", missed),
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        }
+
+        async fn append(&self, change: &CueChange) -> std::io::Result<()> {
+            let mut line = serde_json::to_vec(change)?;
+            line.push(b'\n');
+            // Reopened every time so that the log can be moved away between services
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            file.write_all(&line).await
+        }
+
+        /// Reads all changes in `room`, oldest first. Lines that can't be parsed, e.g. one being written right now,
+        /// are skipped.
+        async fn read(&self, room: &str) -> std::io::Result<Vec<CueChange>> {
+            let text = match tokio::fs::read_to_string(&self.path).await {
+                Ok(text) => text,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(err) => return Err(err),
+            };
+            let mut changes: Vec<CueChange> = text
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .filter(|change: &CueChange| change.room == room)
+                .collect();
+            changes.sort_by_key(|change| change.timestamp);
+            Ok(changes)
+        }
+    }
+
+    /// A cue and how long it was held before the next change
+    #[derive(Clone, Debug, Serialize)]
+    pub struct CueLogRow {
+        pub timestamp: DateTime<Utc>,
+        pub cue: String,
+        pub song: Option<String>,
+        pub held_seconds: f64,
+    }
+
+    /// Total time spent on one section of a song
+    #[derive(Clone, Debug, Serialize)]
+    pub struct SongSectionDuration {
+        pub song: String,
+        pub cue: String,
+        pub times_cued: usize,
+        pub held_seconds: f64,
+    }
+
+    #[derive(Serialize)]
+    struct CueLogExport {
+        room: String,
+        cues: Vec<CueLogRow>,
+        songs: Vec<SongSectionDuration>,
+    }
+
+    /// Rows for the changes in `from..to`. Each change is held until the next change or `to`, whichever comes first.
+    fn cue_rows(changes: &[CueChange], from: Option<DateTime<Utc>>, to: DateTime<Utc>) -> Vec<CueLogRow> {
+        changes
+            .iter()
+            .enumerate()
+            .filter(|(_, change)| from.map_or(true, |from| change.timestamp >= from) && change.timestamp < to)
+            .map(|(index, change)| {
+                let held_until = changes.get(index + 1).map_or(to, |next| next.timestamp.min(to));
+                CueLogRow {
+                    timestamp: change.timestamp,
+                    cue: section_segments_to_string(&change.section),
+                    song: change.song.clone(),
+                    held_seconds: (held_until - change.timestamp).num_milliseconds() as f64 / 1000.0,
+                }
+            })
+            .collect()
+    }
+
+    /// Durations of each section of each song, in order of first appearance. Cleared cues are left out.
+    fn song_durations(rows: &[CueLogRow]) -> Vec<SongSectionDuration> {
+        let mut durations: Vec<SongSectionDuration> = Vec::new();
+        for row in rows {
+            let Some(song) = &row.song else {
+                continue;
+            };
+            if row.cue.is_empty() {
+                continue;
+            }
+            match durations.iter_mut().find(|d| &d.song == song && d.cue == row.cue) {
+                Some(duration) => {
+                    duration.times_cued += 1;
+                    duration.held_seconds += row.held_seconds;
+                }
+                None => durations.push(SongSectionDuration {
+                    song: song.clone(),
+                    cue: row.cue.clone(),
+                    times_cued: 1,
+                    held_seconds: row.held_seconds,
+                }),
+            }
+        }
+        durations
+    }
+
+    fn csv_field(field: &str) -> String {
+        if field.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    }
+
+    fn cues_csv(rows: &[CueLogRow]) -> String {
+        let mut csv = String::from("timestamp,cue,song,held_seconds\r\n");
+        for row in rows {
+            csv.push_str(&format!(
+                "{},{},{},{:.1}\r\n",
+                row.timestamp.to_rfc3339(),
+                csv_field(&row.cue),
+                csv_field(row.song.as_deref().unwrap_or("")),
+                row.held_seconds
+            ));
+        }
+        csv
+    }
+
+    fn songs_csv(durations: &[SongSectionDuration]) -> String {
+        let mut csv = String::from("song,cue,times_cued,held_seconds\r\n");
+        for duration in durations {
+            csv.push_str(&format!(
+                "{},{},{},{:.1}\r\n",
+                csv_field(&duration.song),
+                csv_field(&duration.cue),
+                duration.times_cued,
+                duration.held_seconds
+            ));
+        }
+        csv
+    }
+
+    /// Parses an RFC 3339 timestamp, or a local time as sent by `<input type="datetime-local">`
+    fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+        if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+            return Some(time.with_timezone(&Utc));
+        }
+        let naive = NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S")
+            .or_else(|_| NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M"))
+            .ok()?;
+        Local.from_local_datetime(&naive).earliest().map(|time| time.with_timezone(&Utc))
+    }
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum ExportFormat {
+        #[default]
+        Csv,
+        Json,
+        /// Per-song section durations as CSV
+        SongsCsv,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ExportQuery {
+        room: Option<String>,
+        /// Empty values come from blank form fields and mean no limit
+        from: Option<String>,
+        to: Option<String>,
+        #[serde(default)]
+        format: ExportFormat,
+    }
+
+    /// Exports the cue log of a room for a time range as CSV or JSON
+    pub async fn export_handler(State(cue_log): State<Option<Arc<CueLog>>>, Query(query): Query<ExportQuery>) -> Response {
+        let Some(cue_log) = cue_log else {
+            return (StatusCode::NOT_FOUND, "The cue recorder is disabled").into_response();
+        };
+        let room = query.room.unwrap_or_else(|| DEFAULT_ROOM.to_string());
+        if !is_valid_room_id(&room) {
+            return (StatusCode::BAD_REQUEST, "Invalid room ID").into_response();
+        }
+        let from = match query.from.as_deref().filter(|from| !from.is_empty()).map(parse_time) {
+            Some(None) => return (StatusCode::BAD_REQUEST, "Invalid start time").into_response(),
+            Some(from) => from,
+            None => None,
+        };
+        let to = match query.to.as_deref().filter(|to| !to.is_empty()).map(parse_time) {
+            Some(None) => return (StatusCode::BAD_REQUEST, "Invalid end time").into_response(),
+            Some(Some(to)) => to.min(Utc::now()),
+            None => Utc::now(),
+        };
+
+        let changes = match cue_log.read(&room).await {
+            Ok(changes) => changes,
+            Err(err) => {
+                log::error!("Could not read cue log: {}", err);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Could not read the cue log").into_response();
+            }
+        };
+        let rows = cue_rows(&changes, from, to);
+
+        let (content_type, extension, body) = match query.format {
+            ExportFormat::Csv => ("text/csv; charset=utf-8", "csv", cues_csv(&rows)),
+            ExportFormat::SongsCsv => ("text/csv; charset=utf-8", "songs.csv", songs_csv(&song_durations(&rows))),
+            ExportFormat::Json => {
+                let songs = song_durations(&rows);
+                let export = CueLogExport { room: room.clone(), cues: rows, songs };
+                match serde_json::to_string_pretty(&export) {
+                    Ok(json) => ("application/json", "json", json),
+                    Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+                }
+            }
+        };
+        let disposition = format!("attachment; filename=\"cue-log-{}.{}\"", room, extension);
+
+        ([(header::CONTENT_TYPE, content_type.to_string()), (header::CONTENT_DISPOSITION, disposition)], body).into_response()
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+
+    use super::*;
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, 18, 9, 30, 0).unwrap() + Duration::seconds(seconds)
+    }
+
+    fn change(seconds: i64, code: Option<char>, number: usize, song: Option<&str>) -> CueChange {
+        CueChange {
+            room: "default".to_string(),
+            sequence: seconds as u64,
+            timestamp: at(seconds),
+            section: (code, NonZeroUsize::new(number)),
+            next: (None, None),
+            song: song.map(str::to_string),
+        }
+    }
+
+    fn held(rows: &[CueLogRow]) -> Vec<(&str, f64)> {
+        rows.iter().map(|row| (row.cue.as_str(), row.held_seconds)).collect()
+    }
+
+    #[test]
+    fn holds_cues_until_the_next_change_or_the_end_of_the_range() {
+        let changes = [
+            change(0, Some('V'), 1, None),
+            change(30, Some('C'), 0, None),
+            change(90, Some('V'), 2, None),
+            change(200, Some('C'), 0, None),
+        ];
+        assert_eq!(
+            held(&cue_rows(&changes, None, at(300))),
+            [("V1", 30.0), ("C", 60.0), ("V2", 110.0), ("C", 100.0)]
+        );
+        // C is held past `to`, and V2 starts after it
+        assert_eq!(held(&cue_rows(&changes, Some(at(10)), at(60))), [("C", 30.0)]);
+        // The last cue before `to` only counts up to `to`, although the next change comes later
+        assert_eq!(held(&cue_rows(&changes, Some(at(90)), at(150))), [("V2", 60.0)]);
+    }
+
+    #[test]
+    fn exports_nothing_for_an_empty_range() {
+        let changes = [change(0, Some('V'), 1, None), change(30, Some('C'), 0, None)];
+        assert!(cue_rows(&changes, Some(at(10)), at(20)).is_empty());
+        assert!(cue_rows(&changes, Some(at(30)), at(30)).is_empty());
+        assert!(cue_rows(&[], None, at(30)).is_empty());
+        assert_eq!(cues_csv(&[]), "timestamp,cue,song,held_seconds\r\n");
+        assert_eq!(songs_csv(&song_durations(&[])), "song,cue,times_cued,held_seconds\r\n");
+    }
+
+    #[test]
+    fn groups_song_sections() {
+        let changes = [
+            change(0, Some('V'), 1, Some("Amazing Grace")),
+            change(30, Some('C'), 0, Some("Amazing Grace")),
+            change(60, Some('V'), 2, Some("Amazing Grace")),
+            change(90, Some('C'), 0, Some("Amazing Grace")),
+            change(120, None, 0, Some("Amazing Grace")),
+            change(130, Some('W'), 0, None),
+            change(140, Some('C'), 0, Some("Holy, \"Holy\"")),
+        ];
+        let rows = cue_rows(&changes, None, at(150));
+        assert_eq!(
+            songs_csv(&song_durations(&rows)),
+            "song,cue,times_cued,held_seconds\r\n\
+             Amazing Grace,V1,1,30.0\r\n\
+             Amazing Grace,C,2,60.0\r\n\
+             Amazing Grace,V2,1,30.0\r\n\
+             \"Holy, \"\"Holy\"\"\",C,1,10.0\r\n"
+        );
+        assert!(cues_csv(&rows).ends_with(",W,,10.0\r\n2026-10-18T09:32:20+00:00,C,\"Holy, \"\"Holy\"\"\",10.0\r\n"));
+    }
+
+    #[test]
+    fn exports_cues_and_songs_as_json() {
+        let changes = [change(0, Some('V'), 1, Some("Song")), change(30, Some('V'), 1, Some("Song"))];
+        let cues = cue_rows(&changes, None, at(40));
+        let songs = song_durations(&cues);
+        let export = serde_json::to_value(CueLogExport {
+            room: "default".to_string(),
+            cues,
+            songs,
+        })
+        .unwrap();
+        assert_eq!(export["cues"][1]["timestamp"], "2026-10-18T09:30:30Z");
+        assert_eq!(export["cues"][1]["held_seconds"], 10.0);
+        assert_eq!(
+            export["songs"],
+            serde_json::json!([{ "song": "Song", "cue": "V1", "times_cued": 2, "held_seconds": 40.0 }])
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use axum::extract::{ConnectInfo, Path, Query, State};
+    use axum::response::{IntoResponse, Response};
+    use axum::Json;
+    use chrono::{DateTime, Utc};
+    use http::{HeaderMap, StatusCode};
+    use serde::{Deserialize, Serialize};
+    use thiserror::Error;
+    use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+    use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+
+    use crate::app::{section_segments_to_string, AppState, DirectorState};
+    use crate::audience::parse_groups;
+    use crate::auth::{login_name, Auth, AuthError};
+    use crate::control::{apply_action, ControlAction, ControlError};
+    use crate::rooms::RoomError;
+    use crate::vocabulary::Vocabulary;
+
+    #[derive(Debug, Error)]
+    pub enum RestError {
+        #[error("log in as a director first")]
+        Unauthorized,
+        #[error(transparent)]
+        Room(#[from] RoomError),
+        #[error(transparent)]
+        Control(#[from] ControlError),
+        #[error(transparent)]
+        Auth(#[from] AuthError),
+    }
+
+    impl IntoResponse for RestError {
+        fn into_response(self) -> Response {
+            let status = match &self {
+                RestError::Unauthorized | RestError::Auth(AuthError::WrongPin) => StatusCode::UNAUTHORIZED,
+                RestError::Auth(AuthError::TooManyAttempts(_)) => StatusCode::TOO_MANY_REQUESTS,
+                RestError::Room(_) | RestError::Control(_) | RestError::Auth(AuthError::Disabled) => StatusCode::BAD_REQUEST,
+            };
+            (status, Json(ErrorBody { error: self.to_string() })).into_response()
+        }
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub struct ErrorBody {
+        pub error: String,
+    }
+
+    /// The cues of a room. Cues are written as on the displays, e.g. `V2`, and are empty when not set.
+    #[derive(Debug, Serialize, ToSchema)]
+    pub struct RoomState {
+        pub room: String,
+        /// Number of the latest update of the room
+        pub sequence: u64,
+        pub section: String,
+        /// Long form of the current cue, e.g. `Verse 2`
+        pub label: String,
+        /// Cue staged to follow the current one
+        pub next: String,
+        /// Title of the setlist song the current cue belongs to
+        pub song: Option<String>,
+    }
+
+    impl RoomState {
+        fn new(room: &str, state: &DirectorState, groups: &[String], vocabulary: &Vocabulary) -> Self {
+            let cue = state.cue_for(groups);
+            RoomState {
+                room: room.to_string(),
+                sequence: state.sequence,
+                section: section_segments_to_string(&cue.section),
+                label: vocabulary.label(&cue.section),
+                next: section_segments_to_string(&state.next),
+                song: state
+                    .position
+                    .and_then(|position| state.setlist.songs.get(position.song))
+                    .map(|song| song.title.clone()),
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub struct StateQuery {
+        /// Comma-separated groups whose targeted cues to include, as for displays
+        groups: Option<String>,
+    }
+
+    /// A change of the current cue
+    #[derive(Debug, Serialize, ToSchema)]
+    pub struct HistoryItem {
+        pub timestamp: DateTime<Utc>,
+        pub section: String,
+        pub previous: String,
+    }
+
+    #[derive(Debug, Default, Deserialize, ToSchema)]
+    pub struct ActionRequest {
+        /// Cue for the `set` and `stage` actions, e.g. `V2`
+        cue: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize, ToSchema)]
+    pub struct LoginRequest {
+        pin: String,
+        /// Shown in the audit log with every change made with the token
+        #[serde(default)]
+        name: String,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub struct LoginResponse {
+        /// Session token to send as `Authorization: Bearer <token>`
+        pub token: String,
+    }
+
+    /// Name of the director making the request, from the session cookie or a bearer token
+    fn require_director(auth: &Auth, headers: &HeaderMap) -> Result<String, RestError> {
+        auth.director(headers).ok_or(RestError::Unauthorized)
+    }
+
+    /// Current and staged cues of a room
+    #[utoipa::path(
+        get,
+        path = "/api/v1/rooms/{room}/state",
+        params(("room" = String, Path, description = "Room ID, `default` for the pages without one"), StateQuery),
+        responses(
+            (status = 200, body = RoomState),
+            (status = 400, description = "Invalid room ID", body = ErrorBody),
+        ),
+    )]
+    pub async fn get_state(
+        State(app_state): State<AppState>,
+        Path(room): Path<String>,
+        Query(query): Query<StateQuery>,
+    ) -> Result<Json<RoomState>, RestError> {
+        let state = app_state.rooms.get_or_create(&room)?.state();
+        let groups = query.groups.as_deref().map(parse_groups).unwrap_or_default();
+        Ok(Json(RoomState::new(&room, &state, &groups, &app_state.vocabulary)))
+    }
+
+    /// Changes the cues of a room, the same way as the buttons on the director page
+    #[utoipa::path(
+        post,
+        path = "/api/v1/rooms/{room}/{action}",
+        params(
+            ("room" = String, Path, description = "Room ID, `default` for the pages without one"),
+            ("action" = ControlAction, Path, description = "What to do"),
+            ("cue" = Option<String>, Query, description = "Cue for `set` and `stage` if not given in the body"),
+        ),
+        request_body(content = Option<ActionRequest>, description = "Needed by `set` and `stage`"),
+        responses(
+            (status = 200, description = "The cues after the change", body = RoomState),
+            (status = 400, description = "Invalid room ID or cue", body = ErrorBody),
+            (status = 401, description = "Not logged in as a director", body = ErrorBody),
+        ),
+        security(("bearer" = [])),
+    )]
+    pub async fn post_action(
+        State(app_state): State<AppState>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        Path((room, action)): Path<(String, ControlAction)>,
+        Query(query): Query<ActionRequest>,
+        headers: HeaderMap,
+        body: Option<Json<ActionRequest>>,
+    ) -> Result<Json<RoomState>, RestError> {
+        let name = require_director(&app_state.auth, &headers)?;
+        let cue = body.and_then(|Json(body)| body.cue).or(query.cue);
+        let room_handle = app_state.rooms.get_or_create(&room)?;
+        let source = format!("{} ({})", name, addr.ip());
+        apply_action(&room_handle, &app_state.vocabulary, action, cue.as_deref(), &source)?;
+        Ok(Json(RoomState::new(&room, &room_handle.state(), &[], &app_state.vocabulary)))
+    }
+
+    /// Recent changes of the current cue of a room, newest first
+    #[utoipa::path(
+        get,
+        path = "/api/v1/rooms/{room}/history",
+        params(("room" = String, Path, description = "Room ID, `default` for the pages without one")),
+        responses(
+            (status = 200, body = [HistoryItem]),
+            (status = 400, description = "Invalid room ID", body = ErrorBody),
+            (status = 401, description = "Not logged in as a director", body = ErrorBody),
+        ),
+        security(("bearer" = [])),
+    )]
+    pub async fn get_history(
+        State(app_state): State<AppState>,
+        Path(room): Path<String>,
+        headers: HeaderMap,
+    ) -> Result<Json<Vec<HistoryItem>>, RestError> {
+        require_director(&app_state.auth, &headers)?;
+        let history = app_state
+            .rooms
+            .get_or_create(&room)?
+            .state()
+            .history
+            .iter()
+            .rev()
+            .map(|entry| HistoryItem {
+                timestamp: entry.timestamp,
+                section: section_segments_to_string(&entry.section),
+                previous: section_segments_to_string(&entry.previous),
+            })
+            .collect();
+        Ok(Json(history))
+    }
+
+    /// Starts a director session for API clients, with the same PIN and lockout as the login page
+    #[utoipa::path(
+        post,
+        path = "/api/v1/login",
+        request_body = LoginRequest,
+        responses(
+            (status = 200, body = LoginResponse),
+            (status = 400, description = "No director PIN is configured, so no login is needed", body = ErrorBody),
+            (status = 401, description = "Wrong PIN", body = ErrorBody),
+            (status = 429, description = "Too many failed attempts", body = ErrorBody),
+        ),
+    )]
+    pub async fn post_login(
+        State(auth): State<Arc<Auth>>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        Json(request): Json<LoginRequest>,
+    ) -> Result<Json<LoginResponse>, RestError> {
+        let name = login_name(&request.name);
+        let token = auth.login(addr.ip(), &request.pin, &name).map_err(|err| {
+            log::warn!(target: "audit", "Failed director login as {} from {}: {}", name, addr.ip(), err);
+            err
+        })?;
+        log::info!(target: "audit", "{} logged in as director from {} using the API", name, addr.ip());
+        Ok(Json(LoginResponse { token }))
+    }
+
+    #[derive(OpenApi)]
+    #[openapi(
+        info(title = "Song Sequence Director", description = "Reading and changing the cues of a room"),
+        paths(get_state, post_action, get_history, post_login),
+        components(schemas(RoomState, HistoryItem, ActionRequest, LoginRequest, LoginResponse, ErrorBody, ControlAction)),
+        modifiers(&BearerAuth),
+    )]
+    pub struct ApiDoc;
+
+    struct BearerAuth;
+
+    impl Modify for BearerAuth {
+        fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+            if let Some(components) = openapi.components.as_mut() {
+                components.add_security_scheme(
+                    "bearer",
+                    SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+                );
+            }
+        }
+    }
+
+    /// The OpenAPI description of the REST API
+    pub async fn openapi_handler() -> Json<utoipa::openapi::OpenApi> {
+        Json(ApiDoc::openapi())
+    }
+}}
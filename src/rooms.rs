@@ -0,0 +1,260 @@
+use cfg_if::cfg_if;
+
+/// Room used by the routes without a room ID, i.e. `/` and `/view`
+pub const DEFAULT_ROOM: &str = "default";
+
+/// Room IDs appear in URLs and storage keys, so they are limited to a conservative set of characters
+pub fn is_valid_room_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Path of `page` within `room`, e.g. `view` in room `youth` is `/room/youth/view`
+pub fn room_path(room: &str, page: &str) -> String {
+    match (room == DEFAULT_ROOM, page.is_empty()) {
+        (true, _) => format!("/{}", page),
+        (false, true) => format!("/room/{}", room),
+        (false, false) => format!("/room/{}/{}", room, page),
+    }
+}
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+    use thiserror::Error;
+    use tokio::sync::{broadcast, watch};
+
+    use crate::app::{section_segments_to_string, DirectorState, SectionTuple};
+    use crate::audience::TargetedCue;
+    use crate::persistence::{persist_changes, StateStore};
+    use crate::presence::Presence;
+    use crate::vocabulary::Vocabulary;
+
+    /// Number of cue changes that can be queued for a slow subscriber before it starts missing them
+    const CUE_CHANGE_CAPACITY: usize = 256;
+
+    /// A change of the current cue in any room, sent to subscribers of [`RoomRegistry::subscribe_changes`].
+    /// Unlike the watch channel of each room, every single change is delivered.
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct CueChange {
+        pub room: String,
+        /// Sequence number of the room's state after the change
+        #[serde(default)]
+        pub sequence: u64,
+        pub timestamp: DateTime<Utc>,
+        pub section: SectionTuple,
+        pub next: SectionTuple,
+        /// Title of the setlist song the cue belongs to, if any
+        pub song: Option<String>,
+    }
+
+    impl CueChange {
+        /// `template` with `{section}`, `{next}`, `{label}`, `{song}` and `{room}` replaced by this change
+        pub fn fill_template(&self, template: &str, vocabulary: &Vocabulary) -> String {
+            template
+                .replace("{section}", &section_segments_to_string(&self.section))
+                .replace("{next}", &section_segments_to_string(&self.next))
+                .replace("{label}", &vocabulary.label(&self.section))
+                .replace("{song}", self.song.as_deref().unwrap_or(""))
+                .replace("{room}", &self.room)
+        }
+    }
+
+    #[derive(Debug, Error)]
+    pub enum RoomError {
+        #[error("invalid room ID \"{0}\"")]
+        InvalidId(String),
+    }
+
+    /// A single director's cues, independent of every other room
+    pub struct Room {
+        id: String,
+        section_tx: watch::Sender<DirectorState>,
+        change_tx: broadcast::Sender<CueChange>,
+        presence: Arc<Presence>,
+        last_active: Mutex<Instant>,
+        /// Receivers held by the server itself rather than connected clients
+        internal_receivers: usize,
+    }
+
+    impl Room {
+        pub fn id(&self) -> &str {
+            &self.id
+        }
+
+        pub fn state(&self) -> DirectorState {
+            self.section_tx.borrow().clone()
+        }
+
+        pub fn presence(&self) -> &Arc<Presence> {
+            &self.presence
+        }
+
+        pub fn subscribe(&self) -> watch::Receiver<DirectorState> {
+            self.touch();
+            self.section_tx.subscribe()
+        }
+
+        /// Applies `modify` to the state, notifying subscribers if it returns `true`. Changes of the current cue are
+        /// added to the history.
+        pub fn modify(&self, modify: impl FnOnce(&mut DirectorState) -> bool) -> bool {
+            self.touch();
+            self.section_tx.send_if_modified(|state| {
+                let (previous, previous_next, previous_position) = (state.section, state.next, state.position);
+                if !modify(state) {
+                    return false;
+                }
+                state.sequence += 1;
+                if state.section != previous {
+                    state.cue_sequence = state.sequence;
+                    state.targeted.clear();
+                    let timestamp = Utc::now();
+                    state.record_change(previous, previous_next, previous_position, timestamp);
+                    self.announce_change(state, timestamp);
+                }
+                true
+            })
+        }
+
+        /// Reverts the most recent cue change, atomically with respect to other changes
+        pub fn undo(&self) -> bool {
+            self.touch();
+            self.section_tx.send_if_modified(|state| {
+                if !state.undo() {
+                    return false;
+                }
+                state.sequence += 1;
+                state.cue_sequence = state.sequence;
+                state.targeted.clear();
+                self.announce_change(state, Utc::now());
+                true
+            })
+        }
+
+        /// Sets the cue for the group `audience` only, until the cue for everyone changes
+        pub fn target(&self, audience: String, section: SectionTuple) {
+            self.touch();
+            self.section_tx.send_modify(|state| {
+                state.sequence += 1;
+                let sequence = state.sequence;
+                state.targeted.insert(audience, TargetedCue { section, sequence });
+            });
+        }
+
+        fn announce_change(&self, state: &DirectorState, timestamp: DateTime<Utc>) {
+            let song = state
+                .position
+                .and_then(|position| state.setlist.songs.get(position.song))
+                .map(|song| song.title.clone());
+            // Sending only fails if nobody is subscribed, which is fine
+            let _ = self.change_tx.send(CueChange {
+                room: self.id.clone(),
+                sequence: state.sequence,
+                timestamp,
+                section: state.section,
+                next: state.next,
+                song,
+            });
+        }
+
+        fn touch(&self) {
+            *self.last_active.lock().expect("room activity mutex poisoned") = Instant::now();
+        }
+
+        fn is_idle(&self, idle_timeout: Duration) -> bool {
+            self.section_tx.receiver_count() <= self.internal_receivers
+                && self.last_active.lock().expect("room activity mutex poisoned").elapsed() >= idle_timeout
+        }
+    }
+
+    /// All rooms on the server. Rooms are created when first used and dropped again once idle.
+    pub struct RoomRegistry {
+        rooms: Mutex<HashMap<String, Arc<Room>>>,
+        store: Option<Arc<dyn StateStore>>,
+        change_tx: broadcast::Sender<CueChange>,
+    }
+
+    impl RoomRegistry {
+        pub fn new(store: Option<Arc<dyn StateStore>>) -> Self {
+            RoomRegistry {
+                rooms: Mutex::new(HashMap::new()),
+                store,
+                change_tx: broadcast::channel(CUE_CHANGE_CAPACITY).0,
+            }
+        }
+
+        /// Subscribes to the cue changes of all rooms
+        pub fn subscribe_changes(&self) -> broadcast::Receiver<CueChange> {
+            self.change_tx.subscribe()
+        }
+
+        /// Returns the room with ID `id`, creating it from its saved state if it isn't open yet.
+        /// Must be called from within the Tokio runtime.
+        pub fn get_or_create(&self, id: &str) -> Result<Arc<Room>, RoomError> {
+            if !is_valid_room_id(id) {
+                return Err(RoomError::InvalidId(id.to_string()));
+            }
+
+            let mut rooms = self.rooms.lock().expect("room registry mutex poisoned");
+            if let Some(room) = rooms.get(id) {
+                room.touch();
+                return Ok(room.clone());
+            }
+
+            let saved_state = self.store.as_ref().and_then(|store| {
+                store.load(id).unwrap_or_else(|err| {
+                    log::error!("Couldn't restore state of room {}, starting fresh: {}", id, err);
+                    None
+                })
+            });
+            let (section_tx, section_rx) = watch::channel(saved_state.unwrap_or_default());
+            let mut internal_receivers = 0;
+            if let Some(store) = &self.store {
+                tokio::spawn(persist_changes(store.clone(), id.to_string(), section_rx));
+                internal_receivers += 1;
+            }
+            log::info!("Opened room {}", id);
+
+            let room = Arc::new(Room {
+                id: id.to_string(),
+                section_tx,
+                change_tx: self.change_tx.clone(),
+                presence: Arc::new(Presence::new()),
+                last_active: Mutex::new(Instant::now()),
+                internal_receivers,
+            });
+            rooms.insert(id.to_string(), room.clone());
+            Ok(room)
+        }
+
+        /// Periodically closes rooms that have had no connected clients or activity for `idle_timeout`. Their state
+        /// stays saved, so they are restored if used again. The default room is never closed.
+        /// Also drops the devices using HTTP that have stopped polling from the rooms that stay open.
+        pub async fn close_idle_rooms(self: Arc<Self>, idle_timeout: Duration) {
+            // A zero period would panic, and `idle_timeout = 0` is allowed to close rooms as soon as they're empty
+            let period = idle_timeout.clamp(Duration::from_secs(1), Duration::from_secs(30));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                self.rooms
+                    .lock()
+                    .expect("room registry mutex poisoned")
+                    .retain(|id, room| {
+                        room.presence.expire_leases();
+                        let close = id != DEFAULT_ROOM && room.is_idle(idle_timeout);
+                        if close {
+                            log::info!("Closing idle room {}", id);
+                        }
+                        !close
+                    });
+            }
+        }
+    }
+}}
@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::app::{section_segments_to_string, section_string_to_segments, SectionTuple};
+
+#[derive(Clone, Debug, Error, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SetlistParseError {
+    #[error("line {0}: expected \"Title: V1 C V2 ...\"")]
+    MissingArrangement(usize),
+    #[error("line {0}: song title is empty")]
+    EmptyTitle(usize),
+    #[error("line {0}: invalid section \"{1}\"")]
+    InvalidSection(usize, String),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Song {
+    pub title: String,
+    pub arrangement: Vec<SectionTuple>,
+}
+
+/// Position within a setlist, as indices into [`Setlist::songs`] and the song's arrangement
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetlistPosition {
+    pub song: usize,
+    pub step: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Setlist {
+    pub songs: Vec<Song>,
+}
+
+impl Setlist {
+    /// Parses a setlist with one song per line in the form `Title: V1 C V2 C B C E`
+    pub fn parse(text: &str) -> Result<Self, SetlistParseError> {
+        let mut songs = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            // Split on the last colon so that titles may contain colons
+            let Some((title, arrangement)) = line.rsplit_once(':') else {
+                return Err(SetlistParseError::MissingArrangement(line_number));
+            };
+            let title = title.trim();
+            if title.is_empty() {
+                return Err(SetlistParseError::EmptyTitle(line_number));
+            }
+            let arrangement = arrangement
+                .split_whitespace()
+                .map(|token| {
+                    section_string_to_segments(token)
+                        .ok_or_else(|| SetlistParseError::InvalidSection(line_number, token.to_string()))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            songs.push(Song {
+                title: title.to_string(),
+                arrangement,
+            });
+        }
+
+        Ok(Setlist { songs })
+    }
+
+    /// Inverse of [`Setlist::parse`]
+    pub fn to_text(&self) -> String {
+        self.songs
+            .iter()
+            .map(|song| {
+                let arrangement = song
+                    .arrangement
+                    .iter()
+                    .map(section_segments_to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                format!("{}: {}", song.title, arrangement)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn section_at(&self, position: SetlistPosition) -> Option<SectionTuple> {
+        self.songs
+            .get(position.song)?
+            .arrangement
+            .get(position.step)
+            .copied()
+    }
+
+    /// Position of the first section of the song at `song`, skipping forward over songs without an arrangement
+    pub fn song_start(&self, song: usize) -> Option<SetlistPosition> {
+        self.songs
+            .iter()
+            .enumerate()
+            .skip(song)
+            .find(|(_, s)| !s.arrangement.is_empty())
+            .map(|(song, _)| SetlistPosition { song, step: 0 })
+    }
+
+    /// The position after `position`, moving on to the next song at the end of an arrangement.
+    /// Starts from the top of the setlist if there is no current position.
+    pub fn next_position(&self, position: Option<SetlistPosition>) -> Option<SetlistPosition> {
+        let Some(position) = position else {
+            return self.song_start(0);
+        };
+        let song = self.songs.get(position.song)?;
+        if position.step + 1 < song.arrangement.len() {
+            Some(SetlistPosition {
+                song: position.song,
+                step: position.step + 1,
+            })
+        } else {
+            self.song_start(position.song + 1)
+        }
+    }
+
+    /// The position before `position`, moving back to the end of the previous song at the start of an
+    /// arrangement
+    pub fn previous_position(&self, position: Option<SetlistPosition>) -> Option<SetlistPosition> {
+        let position = position?;
+        if position.step > 0 {
+            return Some(SetlistPosition {
+                song: position.song,
+                step: position.step - 1,
+            });
+        }
+        self.songs
+            .iter()
+            .enumerate()
+            .take(position.song)
+            .rev()
+            .find(|(_, s)| !s.arrangement.is_empty())
+            .map(|(song, s)| SetlistPosition {
+                song,
+                step: s.arrangement.len() - 1,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+
+    fn section(code: char, number: usize) -> SectionTuple {
+        (Some(code), NonZeroUsize::new(number))
+    }
+
+    fn position(song: usize, step: usize) -> Option<SetlistPosition> {
+        Some(SetlistPosition { song, step })
+    }
+
+    #[test]
+    fn parses_songs_and_arrangements() {
+        let text = "Amazing Grace: V1 C v2 C\n\n  Psalm 23: Still Waters :  V1 X  \nIntro:\n";
+        let setlist = Setlist::parse(text).unwrap();
+        assert_eq!(
+            setlist.songs,
+            [
+                Song {
+                    title: "Amazing Grace".to_string(),
+                    arrangement: vec![section('V', 1), section('C', 0), section('V', 2), section('C', 0)],
+                },
+                Song {
+                    title: "Psalm 23: Still Waters".to_string(),
+                    arrangement: vec![section('V', 1), section('X', 0)],
+                },
+                Song {
+                    title: "Intro".to_string(),
+                    arrangement: Vec::new(),
+                },
+            ]
+        );
+        assert_eq!(setlist.to_text(), "Amazing Grace: V1 C V2 C\nPsalm 23: Still Waters: V1 X\nIntro: ");
+        assert_eq!(Setlist::parse(&setlist.to_text()), Ok(setlist));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(
+            Setlist::parse("Amazing Grace: V1\nHow Great Thou Art"),
+            Err(SetlistParseError::MissingArrangement(2))
+        );
+        assert_eq!(Setlist::parse("\n  : V1 C"), Err(SetlistParseError::EmptyTitle(2)));
+        assert_eq!(
+            Setlist::parse("Amazing Grace: V1 Cx V2"),
+            Err(SetlistParseError::InvalidSection(1, "Cx".to_string()))
+        );
+        assert_eq!(Setlist::parse("Amazing Grace: V0"), Err(SetlistParseError::InvalidSection(1, "V0".to_string())));
+    }
+
+    #[test]
+    fn moves_across_songs() {
+        let setlist = Setlist::parse("First: V1 C\nEmpty:\nSecond: B\nThird: V1 V2").unwrap();
+        assert_eq!(setlist.next_position(None), position(0, 0));
+        assert_eq!(setlist.next_position(position(0, 0)), position(0, 1));
+        // Songs without an arrangement are skipped both ways
+        assert_eq!(setlist.next_position(position(0, 1)), position(2, 0));
+        assert_eq!(setlist.next_position(position(2, 0)), position(3, 0));
+        assert_eq!(setlist.next_position(position(3, 1)), None);
+
+        assert_eq!(setlist.previous_position(None), None);
+        assert_eq!(setlist.previous_position(position(3, 1)), position(3, 0));
+        assert_eq!(setlist.previous_position(position(3, 0)), position(2, 0));
+        assert_eq!(setlist.previous_position(position(2, 0)), position(0, 1));
+        assert_eq!(setlist.previous_position(position(0, 0)), None);
+
+        assert_eq!(setlist.song_start(1), position(2, 0));
+        assert_eq!(setlist.song_start(4), None);
+        assert_eq!(setlist.section_at(SetlistPosition { song: 3, step: 1 }), Some(section('V', 2)));
+        assert_eq!(setlist.section_at(SetlistPosition { song: 1, step: 0 }), None);
+    }
+
+    #[test]
+    fn empty_setlist_has_no_positions() {
+        let setlist = Setlist::parse("").unwrap();
+        assert_eq!(setlist.next_position(None), None);
+        assert_eq!(setlist.next_position(position(0, 0)), None);
+        assert_eq!(setlist.previous_position(position(0, 0)), None);
+    }
+}
@@ -0,0 +1,72 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::path::{Path, PathBuf};
+
+    use axum_server::tls_rustls::RustlsConfig;
+    use thiserror::Error;
+
+    use crate::config::TlsConfig;
+
+    #[derive(Debug, Error)]
+    pub enum TlsError {
+        #[error("both a certificate and a key file are needed unless self_signed is set")]
+        MissingFiles,
+        #[error("could not read {0}: {1}")]
+        Read(PathBuf, std::io::Error),
+        #[error("could not write {0}: {1}")]
+        Write(PathBuf, std::io::Error),
+        #[error("could not generate a self-signed certificate: {0}")]
+        Generate(#[from] rcgen::RcgenError),
+        #[error("invalid certificate or key: {0}")]
+        Invalid(std::io::Error),
+    }
+
+    /// Loads the configured certificate and key, generating a self-signed certificate if enabled and there isn't one
+    /// yet. A generated certificate is saved to the configured files, if any, so that browsers that have been told to
+    /// trust it keep doing so after a restart.
+    pub async fn rustls_config(config: &TlsConfig) -> Result<RustlsConfig, TlsError> {
+        let files = config.cert.as_deref().zip(config.key.as_deref());
+        let (cert, key) = match files {
+            Some((cert, key)) if !config.self_signed || (cert.exists() && key.exists()) => {
+                (read(cert).await?, read(key).await?)
+            }
+            _ if !config.self_signed => return Err(TlsError::MissingFiles),
+            _ => {
+                let certificate = rcgen::generate_simple_self_signed(config.hostnames.clone())?;
+                let (cert, key) = (certificate.serialize_pem()?, certificate.serialize_private_key_pem());
+                log::info!("Generated a self-signed certificate for {}", config.hostnames.join(", "));
+                if let Some((cert_path, key_path)) = files {
+                    write(cert_path, &cert).await?;
+                    write_private(key_path, &key).await?;
+                }
+                (cert.into_bytes(), key.into_bytes())
+            }
+        };
+        RustlsConfig::from_pem(cert, key).await.map_err(TlsError::Invalid)
+    }
+
+    async fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+        tokio::fs::read(path).await.map_err(|err| TlsError::Read(path.to_owned(), err))
+    }
+
+    async fn write(path: &Path, contents: &str) -> Result<(), TlsError> {
+        tokio::fs::write(path, contents).await.map_err(|err| TlsError::Write(path.to_owned(), err))
+    }
+
+    /// Like [`write`], but only the server's user can read a file it creates
+    async fn write_private(path: &Path, contents: &str) -> Result<(), TlsError> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let result = async {
+            let mut file = options.open(path).await?;
+            file.write_all(contents.as_bytes()).await?;
+            file.flush().await
+        };
+        result.await.map_err(|err| TlsError::Write(path.to_owned(), err))
+    }
+}}
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::app::SectionTuple;
+use crate::keyboard::KeyBindings;
+
+#[derive(Clone, Debug, Error, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VocabularyError {
+    #[error("\"{0}\" is not a cue in the vocabulary")]
+    UnknownCode(char),
+    #[error("{0} only goes up to {1}")]
+    NumberOutOfRange(char, usize),
+    #[error("cue \"{0}\" is defined more than once")]
+    DuplicateCode(char),
+    #[error("\"{0}\" is not a group in the vocabulary")]
+    UnknownGroup(String),
+    #[error("key \"{0}\" is bound more than once")]
+    DuplicateKey(String),
+    #[error("cue code \"{0}\" is not a capital letter from A to Z")]
+    InvalidCode(char),
+}
+
+/// A cue button on the director page
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CueDefinition {
+    /// Single character shown on the button and the display, e.g. `V`
+    pub code: char,
+    /// Long name of the cue, e.g. `Verse`
+    pub label: String,
+    /// Any CSS colour for the button
+    #[serde(default)]
+    pub color: Option<String>,
+    /// 1-based grid row and column of the button. Buttons without a position fill the gaps in order.
+    #[serde(default)]
+    pub row: Option<u16>,
+    #[serde(default)]
+    pub column: Option<u16>,
+    /// Highest number that can be added to the cue, e.g. 5 to allow V1 to V5. 0 allows no numbers.
+    #[serde(default)]
+    pub numbers: usize,
+    /// Key that presses the button, as named by the browser. Defaults to the code, e.g. `v` for `V`.
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+impl CueDefinition {
+    fn new(code: char, label: &str, row: u16, column: u16) -> Self {
+        CueDefinition {
+            code,
+            label: label.to_string(),
+            color: None,
+            row: Some(row),
+            column: Some(column),
+            numbers: 5,
+            key: None,
+        }
+    }
+}
+
+/// The set of cues a team uses, and how their buttons are laid out
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Vocabulary {
+    /// Number of button columns in landscape orientation, or rows in portrait
+    pub columns: u16,
+    #[serde(rename = "cue")]
+    pub cues: Vec<CueDefinition>,
+    /// Groups that cues can be targeted at, e.g. `Band` or `Drums`
+    pub groups: Vec<String>,
+    /// Keyboard shortcuts for the other buttons of the director page
+    pub keys: KeyBindings,
+}
+
+impl Default for Vocabulary {
+    fn default() -> Self {
+        Vocabulary {
+            columns: 7,
+            cues: vec![
+                CueDefinition::new('C', "Chorus", 1, 1),
+                CueDefinition::new('V', "Verse", 1, 2),
+                CueDefinition::new('B', "Bridge", 1, 3),
+                CueDefinition::new('P', "Pre-chorus", 1, 4),
+                CueDefinition::new('W', "Worship", 1, 5),
+                CueDefinition::new('E', "Ending", 1, 6),
+                CueDefinition::new('X', "Stop", 1, 7),
+                CueDefinition::new('R', "Repeat", 2, 6),
+            ],
+            groups: Vec::new(),
+            keys: KeyBindings::default(),
+        }
+    }
+}
+
+impl Vocabulary {
+    pub fn cue(&self, code: char) -> Option<&CueDefinition> {
+        self.cues.iter().find(|cue| cue.code == code)
+    }
+
+    /// Highest number of any cue, i.e. how many number buttons are needed
+    pub fn max_number(&self) -> usize {
+        self.cues.iter().map(|cue| cue.numbers).max().unwrap_or(0)
+    }
+
+    /// Checks that the vocabulary itself is consistent
+    pub fn check(&self) -> Result<(), VocabularyError> {
+        for (index, cue) in self.cues.iter().enumerate() {
+            // Cues are written as the letter and the number, e.g. `V2`, and read in either case
+            if !cue.code.is_ascii_uppercase() {
+                return Err(VocabularyError::InvalidCode(cue.code));
+            }
+            if self.cues[..index].iter().any(|other| other.code == cue.code) {
+                return Err(VocabularyError::DuplicateCode(cue.code));
+            }
+        }
+        if let Some(key) = self.keys.duplicate(&self.cues) {
+            return Err(VocabularyError::DuplicateKey(key));
+        }
+        Ok(())
+    }
+
+    /// Checks that `section` is made of a known cue and a number within its range. An empty section is always valid.
+    pub fn validate(&self, section: &SectionTuple) -> Result<(), VocabularyError> {
+        let Some(code) = section.0 else {
+            return Ok(());
+        };
+        let cue = self.cue(code).ok_or(VocabularyError::UnknownCode(code))?;
+        match section.1 {
+            Some(number) if number.get() > cue.numbers => Err(VocabularyError::NumberOutOfRange(code, cue.numbers)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn validate_group(&self, group: &str) -> Result<(), VocabularyError> {
+        if self.groups.iter().any(|known| known == group) {
+            Ok(())
+        } else {
+            Err(VocabularyError::UnknownGroup(group.to_string()))
+        }
+    }
+
+    /// Long form of `section`, e.g. `Verse 2`. Unknown cues fall back to their code.
+    pub fn label(&self, section: &SectionTuple) -> String {
+        let Some(code) = section.0 else {
+            return String::new();
+        };
+        let label = self
+            .cue(code)
+            .map(|cue| cue.label.clone())
+            .unwrap_or_else(|| code.to_string());
+        match section.1 {
+            Some(number) => format!("{} {}", label, number),
+            None => label,
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use chrono::{DateTime, Utc};
+    use hmac::{Hmac, Mac};
+    use serde::Serialize;
+    use sha2::Sha256;
+    use thiserror::Error;
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::broadcast::{self, error::RecvError};
+    use tokio::sync::mpsc;
+
+    use crate::app::section_segments_to_string;
+    use crate::config::{WebhookTarget, WebhooksConfig};
+    use crate::rooms::CueChange;
+    use crate::vocabulary::Vocabulary;
+
+    /// Header holding the hex HMAC-SHA256 of the body, keyed with the target's secret, as `sha256=<hex>`
+    pub const SIGNATURE_HEADER: &str = "X-Director-Signature";
+    /// Header identifying a change, the same for every attempt to deliver it
+    pub const DELIVERY_HEADER: &str = "X-Director-Delivery";
+    /// Changes waiting for a slow target before further ones go straight to the dead-letter log
+    const QUEUE_LENGTH: usize = 64;
+    const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+    #[derive(Debug, Error)]
+    pub enum WebhookError {
+        #[error("invalid webhook URL \"{0}\": {1}")]
+        InvalidUrl(String, reqwest::Error),
+        #[error("could not set up HTTP client: {0}")]
+        Client(reqwest::Error),
+    }
+
+    /// The body of a webhook request. Cues are written as on the displays, e.g. `V2`, and are empty when not set.
+    #[derive(Clone, Debug, Serialize)]
+    pub struct WebhookPayload {
+        pub room: String,
+        pub sequence: u64,
+        pub timestamp: DateTime<Utc>,
+        pub section: String,
+        /// Long form of the cue, e.g. `Verse 2`
+        pub label: String,
+        pub next: String,
+        pub song: Option<String>,
+    }
+
+    /// A change that could not be delivered, as written to the dead-letter log
+    #[derive(Serialize)]
+    struct DeadLetter<'a> {
+        failed_at: DateTime<Utc>,
+        url: &'a str,
+        attempts: u32,
+        error: &'a str,
+        payload: &'a WebhookPayload,
+    }
+
+    /// Sends every cue change to the configured targets. Each target has its own queue and delivers in order, so a
+    /// slow or unreachable one delays neither the rooms nor the other targets.
+    pub struct Webhooks {
+        targets: Vec<(WebhookTarget, mpsc::Sender<WebhookPayload>)>,
+        vocabulary: Arc<Vocabulary>,
+        dead_letters: Arc<DeadLetters>,
+    }
+
+    impl Webhooks {
+        /// Checks the targets and starts a delivery task for each
+        pub fn start(config: WebhooksConfig, vocabulary: Arc<Vocabulary>) -> Result<Self, WebhookError> {
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(config.timeout))
+                .build()
+                .map_err(WebhookError::Client)?;
+            let dead_letters = Arc::new(DeadLetters { path: config.dead_letters });
+            let mut targets = Vec::new();
+            for target in config.targets {
+                client
+                    .post(&target.url)
+                    .build()
+                    .map_err(|err| WebhookError::InvalidUrl(target.url.clone(), err))?;
+                let (tx, rx) = mpsc::channel(QUEUE_LENGTH);
+                let delivery = Delivery {
+                    client: client.clone(),
+                    target: target.clone(),
+                    retries: config.retries,
+                    dead_letters: dead_letters.clone(),
+                };
+                tokio::spawn(delivery.run(rx));
+                targets.push((target, tx));
+            }
+            Ok(Webhooks {
+                targets,
+                vocabulary,
+                dead_letters,
+            })
+        }
+
+        /// Queues every cue change for the targets interested in its room until the channel closes
+        pub async fn dispatch(self: Arc<Self>, mut changes: broadcast::Receiver<CueChange>) {
+            loop {
+                let change = match changes.recv().await {
+                    Ok(change) => change,
+                    Err(RecvError::Lagged(missed)) => {
+                        log::warn!("Webhooks missed {} cue changes", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                let payload = WebhookPayload {
+                    room: change.room.clone(),
+                    sequence: change.sequence,
+                    timestamp: change.timestamp,
+                    section: section_segments_to_string(&change.section),
+                    label: self.vocabulary.label(&change.section),
+                    next: section_segments_to_string(&change.next),
+                    song: change.song,
+                };
+                for (target, tx) in &self.targets {
+                    if !target.rooms.is_empty() && !target.rooms.contains(&payload.room) {
+                        continue;
+                    }
+                    if tx.try_send(payload.clone()).is_err() {
+                        self.dead_letters.write(&target.url, 0, "too many deliveries queued", &payload).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Delivers the changes queued for one target
+    struct Delivery {
+        client: reqwest::Client,
+        target: WebhookTarget,
+        retries: u32,
+        dead_letters: Arc<DeadLetters>,
+    }
+
+    impl Delivery {
+        async fn run(self, mut queue: mpsc::Receiver<WebhookPayload>) {
+            while let Some(payload) = queue.recv().await {
+                let body = match serde_json::to_vec(&payload) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        log::error!("Could not encode webhook payload: {}", err);
+                        continue;
+                    }
+                };
+                let delivery_id = format!("{}-{}", payload.room, payload.sequence);
+                let mut delay = FIRST_RETRY_DELAY;
+                let mut attempts = 0;
+                loop {
+                    attempts += 1;
+                    let error = match self.send(&delivery_id, body.clone()).await {
+                        Ok(()) => break,
+                        Err(error) => error,
+                    };
+                    if attempts > self.retries {
+                        log::warn!("Giving up on webhook to {} after {} attempts: {}", self.target.url, attempts, error);
+                        self.dead_letters.write(&self.target.url, attempts, &error, &payload).await;
+                        break;
+                    }
+                    log::debug!("Webhook to {} failed, retrying in {:?}: {}", self.target.url, delay, error);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+
+        async fn send(&self, delivery_id: &str, body: Vec<u8>) -> Result<(), String> {
+            let signature = sign(&self.target.secret, &body);
+            let response = self
+                .client
+                .post(&self.target.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+                .header(DELIVERY_HEADER, delivery_id)
+                .body(body)
+                .send()
+                .await
+                .map_err(|err| err.to_string())?;
+            if response.status().is_success() {
+                Ok(())
+            } else {
+                Err(format!("answered {}", response.status()))
+            }
+        }
+    }
+
+    /// Hex HMAC-SHA256 of `body` keyed with `secret`
+    pub fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Append-only log of the changes that could not be delivered
+    struct DeadLetters {
+        path: PathBuf,
+    }
+
+    impl DeadLetters {
+        async fn write(&self, url: &str, attempts: u32, error: &str, payload: &WebhookPayload) {
+            let letter = DeadLetter {
+                failed_at: Utc::now(),
+                url,
+                attempts,
+                error,
+                payload,
+            };
+            if let Err(err) = self.append(&letter).await {
+                log::error!("Could not write to webhook dead-letter log {}: {}", self.path.display(), err);
+            }
+        }
+
+        async fn append(&self, letter: &DeadLetter<'_>) -> std::io::Result<()> {
+            let mut line = serde_json::to_vec(letter)?;
+            line.push(b'\n');
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            file.write_all(&line).await
+        }
+    }
+}}